@@ -1,8 +1,18 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak;
 use anchor_lang::solana_program::native_token::LAMPORTS_PER_SOL;
-use anchor_lang::system_program::{transfer, Transfer};
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+use anchor_lang::solana_program::{ed25519_program, sysvar};
+use anchor_lang::system_program::{
+    allocate, assign, create_account, transfer, Allocate, Assign, CreateAccount, Transfer,
+};
 use anchor_spl::associated_token::AssociatedToken;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer as SplTransferInstruction};
+use anchor_spl::token::spl_token::instruction::AuthorityType;
+use anchor_spl::token::{
+    self, Burn, Mint, MintTo, SetAuthority, Token, TokenAccount, Transfer as SplTransferInstruction,
+};
 use chainlink_solana as chainlink;
 use std::str::FromStr;
 
@@ -10,6 +20,9 @@ declare_id!("938Ddfngq8N4V2be6Afxkiy2hwMKpGj1vigNHC1p9Ws4");
 
 pub const CHAINLINK_PROGRAM: &str = "HEvSKofvBgfaexv23kMabbYqxasxU3mQ4ibBMEmJWHny";
 pub const CHAINLINK_FEED: &str = "99B2bTijsU6f1GCT73HmdR7HCFFjGMBcPZY6jZ96ynrR"; // (Devnet) - CH31Xns5z3M1cTAbKW34jcxPPciazARpijcHj9rxtemt(Mainnet)
+pub const MAX_ROUNDS: usize = 4;
+/// buyer (32) + presale (32) + expiry (16) + tier (1)
+pub const KYC_MESSAGE_LEN: usize = 81;
 
 #[program]
 mod presale {
@@ -30,6 +43,7 @@ mod presale {
         let current_time = Clock::get()?.unix_timestamp.try_into().unwrap();
 
         require!(start_time > current_time, PresaleErrors::InvalidStartTime);
+        require_valid_rounds(&rounds)?;
 
         presale_account.owner = ctx.accounts.owner.key();
         presale_account.payment_wallet = payment_wallet;
@@ -39,6 +53,7 @@ mod presale {
         presale_account.admin = admin;
         presale_account.usdt_token = usd_token;
         presale_account.payment_wallet_usdt = payment_wallet_usdt;
+        presale_account.status = PresaleStatus::Pending;
 
         Ok(())
     }
@@ -95,6 +110,13 @@ mod presale {
         new_status: bool,
     ) -> Result<()> {
         let presale_account = &mut ctx.accounts.presale_account;
+        presale_account.require_status(&[
+            PresaleStatus::Pending,
+            PresaleStatus::Active,
+            PresaleStatus::Ended,
+            PresaleStatus::Finalized,
+            PresaleStatus::Claiming,
+        ])?;
 
         msg!(
             "Whitelist claim only status changing from {} to {}",
@@ -113,6 +135,9 @@ mod presale {
         new_current_tracker: u128,
     ) -> Result<()> {
         let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
 
         msg!(
             "Round changing from {} to {}",
@@ -141,7 +166,8 @@ mod presale {
         let presale_account = &mut ctx.accounts.presale_account;
 
         require!(
-            presale_account.start_time > current_time,
+            presale_account.start_time > current_time
+                && presale_account.status == PresaleStatus::Pending,
             PresaleErrors::PresaleAlreadyStarted
         );
 
@@ -161,6 +187,14 @@ mod presale {
         new_rounds: Vec<Vec<u128>>,
     ) -> Result<()> {
         let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+        require_valid_rounds(&new_rounds)?;
+        require!(
+            presale_account.current_round < new_rounds[0].len() as u128,
+            PresaleErrors::InvalidLength
+        );
 
         presale_account.rounds = new_rounds;
 
@@ -186,6 +220,10 @@ mod presale {
         new_max_tokens_to_buy: u128,
     ) -> Result<()> {
         let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
         msg!(
             "Max tokens to buy changing from {} to {}",
             presale_account.max_tokens_to_buy,
@@ -233,12 +271,259 @@ mod presale {
         Ok(price_in_sol)
     }
 
+    pub fn remaining_allowance(ctx: Context<RemainingAllowance>) -> Result<u128> {
+        let presale_account = &ctx.accounts.presale_account;
+        let (purchased, round_purchased) = match &ctx.accounts.user_account {
+            Some(user_account) => (
+                user_account.purchased_amount,
+                user_account.round_purchased_amount(presale_account.current_round),
+            ),
+            None => (0, 0),
+        };
+
+        Ok(presale_account.wallet_allowance(purchased, round_purchased))
+    }
+
+    pub fn change_wallet_caps(
+        ctx: Context<UpdatePresaleState>,
+        new_max_tokens_per_wallet: u128,
+        new_max_tokens_per_wallet_per_round: u128,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
+        msg!(
+            "Max tokens per wallet changing from {} to {}",
+            presale_account.max_tokens_per_wallet,
+            new_max_tokens_per_wallet
+        );
+        msg!(
+            "Max tokens per wallet per round changing from {} to {}",
+            presale_account.max_tokens_per_wallet_per_round,
+            new_max_tokens_per_wallet_per_round
+        );
+
+        presale_account.max_tokens_per_wallet = new_max_tokens_per_wallet;
+        presale_account.max_tokens_per_wallet_per_round = new_max_tokens_per_wallet_per_round;
+
+        Ok(())
+    }
+
+    pub fn change_min_purchase_usd(
+        ctx: Context<UpdatePresaleState>,
+        new_min_purchase_usd: Vec<u128>,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
+        require!(
+            new_min_purchase_usd.len() <= presale_account.rounds[0].len(),
+            PresaleErrors::InvalidRoundConfig
+        );
+
+        msg!(
+            "Min purchase USD changing from {:?} to {:?}",
+            presale_account.min_purchase_usd,
+            new_min_purchase_usd
+        );
+
+        presale_account.min_purchase_usd = new_min_purchase_usd;
+
+        Ok(())
+    }
+
+    pub fn change_bonus_tiers(
+        ctx: Context<UpdatePresaleState>,
+        new_bonus_tiers: Vec<BonusTier>,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
+        require!(
+            new_bonus_tiers.len() <= 4
+                && new_bonus_tiers.iter().all(|tier| tier.bonus_bps <= 10_000)
+                && new_bonus_tiers
+                    .windows(2)
+                    .all(|tiers| tiers[0].min_usd < tiers[1].min_usd),
+            PresaleErrors::InvalidBonusTiers
+        );
+
+        msg!(
+            "Bonus tiers changing from {:?} to {:?}",
+            presale_account.bonus_tiers,
+            new_bonus_tiers
+        );
+
+        presale_account.bonus_tiers = new_bonus_tiers;
+
+        Ok(())
+    }
+
+    pub fn change_early_bird_bonus(
+        ctx: Context<UpdatePresaleState>,
+        new_bonus_bps: u16,
+        new_duration: u128,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
+        require!(
+            new_bonus_bps <= 10_000,
+            PresaleErrors::InvalidEarlyBirdBonus
+        );
+
+        msg!(
+            "Early bird bonus changing from {} bps over {} to {} bps over {}",
+            presale_account.early_bird_bonus_bps,
+            presale_account.early_bird_duration,
+            new_bonus_bps,
+            new_duration
+        );
+
+        presale_account.early_bird_bonus_bps = new_bonus_bps;
+        presale_account.early_bird_duration = new_duration;
+
+        Ok(())
+    }
+
+    pub fn change_round_merkle_roots(
+        ctx: Context<UpdatePresaleState>,
+        new_round_merkle_roots: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
+        require!(
+            new_round_merkle_roots.len() <= presale_account.rounds[0].len(),
+            PresaleErrors::InvalidRoundConfig
+        );
+
+        presale_account.round_merkle_roots = new_round_merkle_roots;
+
+        msg!("Round merkle roots updated");
+
+        Ok(())
+    }
+
+    pub fn change_kyc_attester(
+        ctx: Context<UpdatePresaleState>,
+        new_kyc_attester: Pubkey,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+
+        msg!(
+            "KYC attester changing from {} to {}",
+            presale_account.kyc_attester,
+            new_kyc_attester
+        );
+
+        presale_account.kyc_attester = new_kyc_attester;
+
+        Ok(())
+    }
+
+    pub fn change_referral_reward(
+        ctx: Context<UpdatePresaleState>,
+        new_reward_bps: u16,
+        new_reward_mode: ReferralRewardMode,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
+        require!(
+            new_reward_bps <= 10_000,
+            PresaleErrors::InvalidReferralReward
+        );
+
+        msg!(
+            "Referral reward changing from {} bps ({:?}) to {} bps ({:?})",
+            presale_account.referral_reward_bps,
+            presale_account.referral_reward_mode,
+            new_reward_bps,
+            new_reward_mode
+        );
+
+        presale_account.referral_reward_bps = new_reward_bps;
+        presale_account.referral_reward_mode = new_reward_mode;
+
+        Ok(())
+    }
+
+    pub fn register_referrer(ctx: Context<RegisterReferrer>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
+        let referrer_account = &mut ctx.accounts.referrer_account;
+        referrer_account.referrer = ctx.accounts.referrer.key();
+        referrer_account.registered_at = current_time;
+
+        msg!("Referrer registered {}", referrer_account.referrer);
+
+        Ok(())
+    }
+
+    pub fn claim_referral_rewards(ctx: Context<ClaimReferralRewards>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let referrer_account = &mut ctx.accounts.referrer_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.require_status(&[PresaleStatus::Claiming])?;
+        require!(
+            ctx.accounts.denylist_entry.data_is_empty(),
+            PresaleErrors::WalletDenylisted
+        );
+        require!(
+            !presale_account.claim_window_closed(current_time),
+            PresaleErrors::ClaimWindowClosed
+        );
+        require!(
+            current_time > presale_account.claim_start_time,
+            PresaleErrors::ClaimNotStarted
+        );
+        require!(!presale_account.is_paused, PresaleErrors::PresalePaused);
+
+        let claimable = referrer_account.reward_tokens - referrer_account.claimed_reward_tokens;
+        require!(claimable > 0, PresaleErrors::NothingToClaim);
+
+        release_claimed_tokens(
+            presale_account,
+            &ctx.accounts.presale_ata,
+            &ctx.accounts.referrer_ata,
+            &ctx.accounts.sale_token,
+            &ctx.accounts.token_program,
+            ctx.bumps.presale_account,
+            claimable,
+        )?;
+        referrer_account.claimed_reward_tokens += claimable;
+
+        msg!("Referral reward tokens claimed are {}", claimable);
+
+        Ok(())
+    }
+
     pub fn change_round_time(
         ctx: Context<UpdatePresaleState>,
         new_dynamic_time_flag: bool,
         new_round_time: u128,
     ) -> Result<()> {
         let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
 
         msg!(
             "Dynamic time change updating from {} to {}",
@@ -257,79 +542,87 @@ mod presale {
         Ok(())
     }
 
-    pub fn buy_with_sol(ctx: Context<BuyWithSol>, amount: u128) -> Result<()> {
+    pub fn buy_with_sol(
+        ctx: Context<BuyWithSol>,
+        amount: u128,
+        allow_partial: bool,
+        allowlist_proof: Option<AllowlistProof>,
+        beneficiary: Option<Pubkey>,
+    ) -> Result<u128> {
+        let presale_key = ctx.accounts.presale_account.key();
+        let beneficiary = beneficiary.unwrap_or(ctx.accounts.user.key());
+        let rent_payer = ctx
+            .accounts
+            .fee_payer
+            .as_ref()
+            .map_or(ctx.accounts.user.key(), |fee_payer| fee_payer.key());
         let presale_account = &mut ctx.accounts.presale_account;
         let user_account = &mut ctx.accounts.user_account;
         let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        user_account.record_rent_payer(rent_payer);
 
-        require!(
-            current_time > presale_account.start_time,
-            PresaleErrors::PresaleNotStarted
-        );
-        require!(amount > 0, PresaleErrors::InvalidBuyAmount);
-        require!(!presale_account.is_paused, PresaleErrors::PresalePaused);
         require!(
             presale_account.payment_wallet == ctx.accounts.payment_wallet.key(),
             PresaleErrors::IncorrectPaymentWallet
         );
+        require!(
+            ctx.accounts.denylist_entry.data_is_empty()
+                && ctx.accounts.beneficiary_denylist_entry.data_is_empty(),
+            PresaleErrors::WalletDenylisted
+        );
 
-        let price_and_round = calculate_price_internal(&presale_account, amount)?;
-        require!(price_and_round.price_in_usd > 0, PresaleErrors::ZeroPrice);
-
-        if price_and_round.new_round != presale_account.current_round {
-            // Update all rounds from now with the new time
-            if presale_account.dynamic_time_change {
-                for i in 0..presale_account.rounds[2].len() - price_and_round.new_round as usize {
-                    presale_account.rounds[2][(price_and_round.new_round + i as u128) as usize] =
-                        current_time + ((i + 1) as u128 * (presale_account.per_round_time));
-                }
-            }
-
-            // Update unsold tokens tracker
-            let sale_amount = if presale_account.current_tracker == 0 {
-                presale_account.tokens_sold + amount
-            } else {
-                presale_account.current_tracker + amount
-            };
-
-            for i in 0..(price_and_round.new_round - presale_account.current_round) {
-                if i == 0 {
-                    let value = if sale_amount
-                        > presale_account.rounds[0][presale_account.current_round as usize]
-                    {
-                        0
-                    } else {
-                        presale_account.rounds[0][presale_account.current_round as usize]
-                            - sale_amount
-                    };
-                    presale_account.unsold_tokens.push(value);
-                } else {
-                    let value1 = presale_account.rounds[0]
-                        [(presale_account.current_round + i) as usize]
-                        - presale_account.rounds[0]
-                            [(presale_account.current_round + i - 1) as usize];
-
-                    presale_account.unsold_tokens.push(value1);
-                }
-            }
+        if let Some(kyc_tier) = verify_kyc_attestation(
+            presale_account,
+            presale_key,
+            &ctx.accounts.instructions,
+            beneficiary,
+            current_time,
+        )? {
+            user_account.kyc_tier = kyc_tier;
+        }
 
-            if current_time >= presale_account.rounds[2][presale_account.current_round as usize] {
-                presale_account.current_tracker =
-                    presale_account.rounds[0][(price_and_round.new_round - 1) as usize];
+        let discount = match &mut ctx.accounts.discount_code {
+            Some(discount_code) => {
+                let discount_redemption = ctx
+                    .accounts
+                    .discount_redemption
+                    .as_mut()
+                    .ok_or(PresaleErrors::MissingOptionalAccount)?;
+                Some(redeem_discount_code(
+                    discount_code,
+                    discount_redemption,
+                    current_time,
+                )?)
             }
+            None => None,
+        };
 
-            presale_account.current_round = price_and_round.new_round;
-        }
-
-        presale_account.usd_raised += price_and_round.price_in_usd;
-        presale_account.tokens_sold += amount;
+        let (price_and_round, amount, bonus_amount) = process_purchase(
+            presale_account,
+            user_account,
+            PurchaseOrder {
+                payer: ctx.accounts.user.key(),
+                beneficiary,
+                amount,
+                allow_partial,
+                allowlist_proof,
+                discount,
+            },
+            current_time,
+        )?;
 
-        if presale_account.current_tracker != 0 {
-            presale_account.current_tracker += amount;
+        if presale_account.instant_delivery {
+            deliver_sale_tokens(
+                presale_account,
+                user_account,
+                &ctx.accounts.presale_ata,
+                &ctx.accounts.user_sale_ata,
+                &ctx.accounts.token_program,
+                ctx.bumps.presale_account,
+                amount + bonus_amount,
+            )?;
         }
 
-        user_account.purchased_amount += amount;
-
         let sol_usd = (chainlink::latest_round_data(
             ctx.accounts.chainlink_program.to_account_info(),
             ctx.accounts.chainlink_feed.to_account_info(),
@@ -339,10 +632,48 @@ mod presale {
 
         msg!("Sol price is USD {}", sol_usd);
 
-        let price_in_sol = (price_and_round.price_in_usd * LAMPORTS_PER_SOL as u128) / sol_usd;
+        let mut price_in_sol = (price_and_round.price_in_usd * LAMPORTS_PER_SOL as u128) / sol_usd;
 
         msg!("Final sol price is  {}", price_in_sol);
 
+        if let Some(referrer_account) = &mut ctx.accounts.referrer_account {
+            let referral_payment_bps = credit_referral(
+                presale_account,
+                referrer_account,
+                ctx.accounts.user.key(),
+                beneficiary,
+                amount,
+                price_and_round.price_in_usd,
+                current_time,
+            )?;
+
+            if referral_payment_bps > 0 {
+                let referrer = ctx
+                    .accounts
+                    .referrer
+                    .as_ref()
+                    .ok_or(PresaleErrors::MissingOptionalAccount)?;
+                require!(
+                    referrer.key() == referrer_account.referrer,
+                    PresaleErrors::InvalidReferrer
+                );
+
+                let referral_payment = price_in_sol * referral_payment_bps / 10_000;
+                price_in_sol -= referral_payment;
+
+                transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        Transfer {
+                            from: ctx.accounts.user.to_account_info(),
+                            to: referrer.to_account_info(),
+                        },
+                    ),
+                    referral_payment as u64,
+                )?;
+            }
+        }
+
         let cpi_context = CpiContext::new(
             ctx.accounts.system_program.to_account_info(),
             Transfer {
@@ -359,86 +690,133 @@ mod presale {
         // } else {
         //     return err!(PresaleErrors::SolTransferFailed);
         // }
-        Ok(())
+        Ok(amount)
     }
 
-    pub fn buy_with_usdt(ctx: Context<BuyWithUSDT>, amount: u128) -> Result<()> {
+    pub fn buy_with_usdt(
+        ctx: Context<BuyWithUSDT>,
+        amount: u128,
+        allow_partial: bool,
+        allowlist_proof: Option<AllowlistProof>,
+        beneficiary: Option<Pubkey>,
+    ) -> Result<u128> {
+        let presale_key = ctx.accounts.presale_account.key();
+        let beneficiary = beneficiary.unwrap_or(ctx.accounts.user.key());
+        let rent_payer = ctx
+            .accounts
+            .fee_payer
+            .as_ref()
+            .map_or(ctx.accounts.user.key(), |fee_payer| fee_payer.key());
         let presale_account = &mut ctx.accounts.presale_account;
         let user_account = &mut ctx.accounts.user_account;
         let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        user_account.record_rent_payer(rent_payer);
 
-        require!(
-            current_time > presale_account.start_time,
-            PresaleErrors::PresaleNotStarted
-        );
-        require!(amount > 0, PresaleErrors::InvalidBuyAmount);
-        require!(!presale_account.is_paused, PresaleErrors::PresalePaused);
         require!(
             presale_account.payment_wallet_usdt == ctx.accounts.payment_wallet_usdt.key(),
             PresaleErrors::IncorrectPaymentWallet
         );
+        require!(
+            ctx.accounts.denylist_entry.data_is_empty()
+                && ctx.accounts.beneficiary_denylist_entry.data_is_empty(),
+            PresaleErrors::WalletDenylisted
+        );
 
-        let price_and_round = calculate_price_internal(&presale_account, amount)?;
-        require!(price_and_round.price_in_usd > 0, PresaleErrors::ZeroPrice);
-
-        if price_and_round.new_round != presale_account.current_round {
-            // Update all rounds from now with the new time
-            if presale_account.dynamic_time_change {
-                for i in 0..presale_account.rounds[2].len() - price_and_round.new_round as usize {
-                    presale_account.rounds[2][(price_and_round.new_round + i as u128) as usize] =
-                        current_time + ((i + 1) as u128 * (presale_account.per_round_time));
-                }
-            }
-
-            // Update unsold tokens tracker
-            let sale_amount = if presale_account.current_tracker == 0 {
-                presale_account.tokens_sold + amount
-            } else {
-                presale_account.current_tracker + amount
-            };
-
-            for i in 0..(price_and_round.new_round - presale_account.current_round) {
-                if i == 0 {
-                    let value = if sale_amount
-                        > presale_account.rounds[0][presale_account.current_round as usize]
-                    {
-                        0
-                    } else {
-                        presale_account.rounds[0][presale_account.current_round as usize]
-                            - sale_amount
-                    };
-                    presale_account.unsold_tokens.push(value);
-                } else {
-                    let value1 = presale_account.rounds[0]
-                        [(presale_account.current_round + i) as usize]
-                        - presale_account.rounds[0]
-                            [(presale_account.current_round + i - 1) as usize];
-
-                    presale_account.unsold_tokens.push(value1);
-                }
-            }
+        if let Some(kyc_tier) = verify_kyc_attestation(
+            presale_account,
+            presale_key,
+            &ctx.accounts.instructions,
+            beneficiary,
+            current_time,
+        )? {
+            user_account.kyc_tier = kyc_tier;
+        }
 
-            if current_time >= presale_account.rounds[2][presale_account.current_round as usize] {
-                presale_account.current_tracker =
-                    presale_account.rounds[0][(price_and_round.new_round - 1) as usize];
+        let discount = match &mut ctx.accounts.discount_code {
+            Some(discount_code) => {
+                let discount_redemption = ctx
+                    .accounts
+                    .discount_redemption
+                    .as_mut()
+                    .ok_or(PresaleErrors::MissingOptionalAccount)?;
+                Some(redeem_discount_code(
+                    discount_code,
+                    discount_redemption,
+                    current_time,
+                )?)
             }
+            None => None,
+        };
 
-            presale_account.current_round = price_and_round.new_round;
-        }
-
-        presale_account.usd_raised += price_and_round.price_in_usd;
-        presale_account.tokens_sold += amount;
+        let (price_and_round, amount, bonus_amount) = process_purchase(
+            presale_account,
+            user_account,
+            PurchaseOrder {
+                payer: ctx.accounts.user.key(),
+                beneficiary,
+                amount,
+                allow_partial,
+                allowlist_proof,
+                discount,
+            },
+            current_time,
+        )?;
 
-        if presale_account.current_tracker != 0 {
-            presale_account.current_tracker += amount;
+        if presale_account.instant_delivery {
+            deliver_sale_tokens(
+                presale_account,
+                user_account,
+                &ctx.accounts.presale_ata,
+                &ctx.accounts.user_sale_ata,
+                &Some(ctx.accounts.token_program.clone()),
+                ctx.bumps.presale_account,
+                amount + bonus_amount,
+            )?;
         }
 
-        user_account.purchased_amount += amount;
-
-        let price_in_usdt = (price_and_round.price_in_usd) / (1000); // To accomodate for USDT's 6 decimals
+        let mut price_in_usdt = (price_and_round.price_in_usd) / (1000); // To accomodate for USDT's 6 decimals
 
         msg!("Price in USDT is {}", price_in_usdt);
 
+        if let Some(referrer_account) = &mut ctx.accounts.referrer_account {
+            let referral_payment_bps = credit_referral(
+                presale_account,
+                referrer_account,
+                ctx.accounts.user.key(),
+                beneficiary,
+                amount,
+                price_and_round.price_in_usd,
+                current_time,
+            )?;
+
+            if referral_payment_bps > 0 {
+                let referrer_usdt_ata = ctx
+                    .accounts
+                    .referrer_usdt_ata
+                    .as_ref()
+                    .ok_or(PresaleErrors::MissingOptionalAccount)?;
+                require!(
+                    referrer_usdt_ata.owner == referrer_account.referrer,
+                    PresaleErrors::InvalidReferrer
+                );
+
+                let referral_payment = price_in_usdt * referral_payment_bps / 10_000;
+                price_in_usdt -= referral_payment;
+
+                token::transfer(
+                    CpiContext::new(
+                        ctx.accounts.token_program.to_account_info(),
+                        SplTransferInstruction {
+                            from: ctx.accounts.user_usdt_ata.to_account_info(),
+                            to: referrer_usdt_ata.to_account_info(),
+                            authority: ctx.accounts.user.to_account_info(),
+                        },
+                    ),
+                    referral_payment as u64,
+                )?;
+            }
+        }
+
         let cpi_accounts = SplTransferInstruction {
             from: ctx.accounts.user_usdt_ata.to_account_info(),
             to: ctx.accounts.payment_wallet_usdt.to_account_info(),
@@ -450,160 +828,1789 @@ mod presale {
             CpiContext::new(cpi_program, cpi_accounts),
             price_in_usdt as u64,
         )?;
-        Ok(())
+        Ok(amount)
     }
 
-    pub fn start_claim(
-        ctx: Context<StartClaim>,
-        claim_start: u128,
-        tokens_to_add: u128,
-        decimals: u8,
+    pub fn record_external_purchase(
+        ctx: Context<RecordExternalPurchase>,
+        user: Pubkey,
+        amount: u128,
+        usd_value: u128,
+        external_ref: [u8; 32],
     ) -> Result<()> {
         let presale_account = &mut ctx.accounts.presale_account;
-        let current_time = Clock::get()?.unix_timestamp.try_into().unwrap();
+        let user_account = &mut ctx.accounts.user_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        let authority = ctx.accounts.authority.key();
+        user_account.record_rent_payer(authority);
 
         require!(
-            presale_account.current_round >= presale_account.rounds[0].len() as u128,
-            PresaleErrors::PresaleNotEnded
+            authority == presale_account.owner
+                || authority == presale_account.admin
+                || (presale_account.kyc_attester != Pubkey::default()
+                    && authority == presale_account.kyc_attester),
+            PresaleErrors::AccessRestricted,
         );
 
         require!(
-            tokens_to_add >= presale_account.tokens_sold,
-            PresaleErrors::IncorrectSaleTokenAdded
+            current_time > presale_account.start_time,
+            PresaleErrors::PresaleNotStarted
+        );
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Active])?;
+        require!(amount > 0, PresaleErrors::InvalidBuyAmount);
+        require!(!presale_account.is_paused, PresaleErrors::PresalePaused);
+        require!(
+            ctx.accounts.denylist_entry.data_is_empty(),
+            PresaleErrors::WalletDenylisted
         );
 
+        // Round and sellout checks as for on-chain buys, priced at the reported USD value
+        let mut price_and_round = calculate_price_internal(presale_account, amount)?;
+        price_and_round.price_in_usd = usd_value;
+
+        // Off-chain payments are already settled, so they are never trimmed
         require!(
-            claim_start > current_time,
-            PresaleErrors::IncorrectClaimStartTime,
+            presale_account.hard_cap_usd == 0
+                || presale_account.usd_raised + usd_value <= presale_account.hard_cap_usd,
+            PresaleErrors::HardCapReached
         );
+        require_wallet_allowance(
+            presale_account,
+            user_account,
+            price_and_round.new_round,
+            amount,
+            current_time,
+        )?;
 
-        require!(decimals > 0, PresaleErrors::ZeroDecimals);
+        record_purchase(
+            presale_account,
+            user_account,
+            &price_and_round,
+            amount,
+            current_time,
+        );
 
-        presale_account.claim_start_time = claim_start;
-        presale_account.sale_token = ctx.accounts.sale_token.key();
-        presale_account.sale_token_decimals = decimals;
-        presale_account.tokens_added = tokens_to_add;
-        presale_account.whitelist_claim_only = true;
+        let external_purchase = &mut ctx.accounts.external_purchase;
+        external_purchase.user = user;
+        external_purchase.amount = amount;
+        external_purchase.usd_value = usd_value;
+        external_purchase.external_ref = external_ref;
+        external_purchase.recorded_at = current_time;
 
-        let transfer_instruction = SplTransferInstruction {
-            from: ctx.accounts.owner_ata.to_account_info(),
-            to: ctx.accounts.presale_ata.to_account_info(),
-            authority: ctx.accounts.owner.to_account_info(),
-        };
+        msg!(
+            "External purchase of {} tokens for USD {} recorded for {}",
+            amount,
+            usd_value,
+            user
+        );
 
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                transfer_instruction,
-            ),
-            (tokens_to_add * 10_u64.pow(decimals as u32) as u128) as u64,
-        )?;
+        emit!(ExternalPurchaseRecorded {
+            user,
+            amount,
+            usd_value,
+            external_ref,
+            round: price_and_round.new_round,
+            recorded_by: authority,
+            timestamp: current_time,
+        });
+
+        if presale_account.hard_cap_reached() {
+            presale_account.end_sale(current_time);
+        }
 
         Ok(())
     }
 
-    pub fn claim(ctx: Context<Claim>) -> Result<()> {
+    pub fn import_allocations<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ImportAllocations<'info>>,
+        entries: Vec<AllocationEntry>,
+    ) -> Result<()> {
         let presale_account = &mut ctx.accounts.presale_account;
         let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
-        let user_account = &mut ctx.accounts.user_account;
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[
+            PresaleStatus::Pending,
+            PresaleStatus::Active,
+            PresaleStatus::Ended,
+            PresaleStatus::Finalized,
+        ])?;
+
+        // One user account per entry, in the same order
+        require!(
+            entries.len() == ctx.remaining_accounts.len(),
+            PresaleErrors::AllocationAccountsMismatch
+        );
 
-        if presale_account.whitelist_claim_only {
+        for (entry, account_info) in entries.iter().zip(ctx.remaining_accounts.iter()) {
+            let (user_account_key, bump) = Pubkey::find_program_address(
+                &[b"user_account", entry.wallet.as_ref()],
+                ctx.program_id,
+            );
             require!(
-                presale_account
-                    .whitelisted_users
-                    .contains(&ctx.accounts.user.key()),
-                PresaleErrors::NotClaimWhitelisted
+                account_info.key() == user_account_key,
+                PresaleErrors::AllocationAccountsMismatch
             );
-        } else {
+
+            let is_new_account = account_info.data_is_empty();
+            if is_new_account {
+                create_program_account(
+                    &ctx.accounts.owner,
+                    account_info,
+                    &ctx.accounts.system_program,
+                    8 + UserAccount::INIT_SPACE,
+                    &[b"user_account", entry.wallet.as_ref(), &[bump]],
+                    ctx.program_id,
+                )?;
+                UserAccount::default()
+                    .try_serialize(&mut &mut account_info.try_borrow_mut_data()?[..])?;
+            }
+
+            let mut user_account = Account::<UserAccount>::try_from(account_info)?;
+            if is_new_account {
+                user_account.vesting_class = entry.vesting_class;
+                user_account.record_rent_payer(ctx.accounts.owner.key());
+            }
             require!(
-                current_time > presale_account.claim_start_time
-                    && presale_account.claim_start_time != 0,
-                PresaleErrors::ClaimNotStarted,
+                user_account.vesting_class == entry.vesting_class,
+                PresaleErrors::VestingClassMismatch
             );
+            user_account.private_amount += entry.amount;
+            user_account.exit(ctx.program_id)?;
+
+            presale_account.private_allocated += entry.amount;
+
+            emit!(AllocationImported {
+                wallet: entry.wallet,
+                amount: entry.amount,
+                vesting_class: entry.vesting_class,
+                timestamp: current_time,
+            });
         }
 
-        require!(
-            user_account.purchased_amount > 0,
-            PresaleErrors::NothingToClaim
+        msg!(
+            "Imported {} allocations, private allocated is {}",
+            entries.len(),
+            presale_account.private_allocated
         );
 
-        require!(!presale_account.is_paused, PresaleErrors::PresalePaused);
-
-        let transfer_instruction = SplTransferInstruction {
-            from: ctx.accounts.presale_ata.to_account_info(),
-            to: ctx.accounts.user_ata.to_account_info(),
-            authority: presale_account.to_account_info(),
-        };
+        Ok(())
+    }
 
-        token::transfer(
+    pub fn adjust_allocation(
+        ctx: Context<AdjustAllocation>,
+        wallet: Pubkey,
+        new_purchased_amount: u128,
+        reason_code: u16,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let user_account = &mut ctx.accounts.user_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        // Round trackers and usd_raised are settled once the sale has ended
+        presale_account.require_status(&[PresaleStatus::Ended, PresaleStatus::Finalized])?;
+
+        let previous_purchased_amount = user_account.purchased_amount;
+        let previous_tokens_sold = presale_account.tokens_sold;
+
+        // Bonuses were granted on the purchased amount, so they follow it. usd_raised
+        // stays as paid since the hard cap no longer applies.
+        let previous_bonus_amount = user_account.bonus_amount;
+        let previous_early_bird_bonus_amount = user_account.early_bird_bonus_amount;
+        user_account.bonus_amount = scale_bonus(
+            previous_bonus_amount,
+            new_purchased_amount,
+            previous_purchased_amount,
+        )?;
+        user_account.early_bird_bonus_amount = scale_bonus(
+            previous_early_bird_bonus_amount,
+            new_purchased_amount,
+            previous_purchased_amount,
+        )?;
+        user_account.purchased_amount = new_purchased_amount;
+
+        // Tokens already claimed or reclaimed can't be taken back
+        require!(
+            user_account.entitled_amount()
+                >= user_account.claimed_amount + user_account.reclaimed_amount,
+            PresaleErrors::InvalidAllocationAdjustment
+        );
+
+        presale_account.tokens_sold = presale_account
+            .tokens_sold
+            .checked_add(new_purchased_amount)
+            .and_then(|tokens_sold| tokens_sold.checked_sub(previous_purchased_amount))
+            .ok_or(PresaleErrors::MathOverflow)?;
+        presale_account.bonus_tokens_allocated = presale_account
+            .bonus_tokens_allocated
+            .checked_add(user_account.bonus_amount)
+            .and_then(|allocated| allocated.checked_sub(previous_bonus_amount))
+            .ok_or(PresaleErrors::MathOverflow)?;
+        presale_account.early_bird_tokens_allocated = presale_account
+            .early_bird_tokens_allocated
+            .checked_add(user_account.early_bird_bonus_amount)
+            .and_then(|allocated| allocated.checked_sub(previous_early_bird_bonus_amount))
+            .ok_or(PresaleErrors::MathOverflow)?;
+
+        msg!(
+            "Allocation of {} adjusted from {} to {} with reason {}",
+            wallet,
+            previous_purchased_amount,
+            new_purchased_amount,
+            reason_code
+        );
+
+        emit!(AllocationAdjusted {
+            wallet,
+            reason_code,
+            previous_purchased_amount,
+            new_purchased_amount,
+            previous_tokens_sold,
+            new_tokens_sold: presale_account.tokens_sold,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn move_allocation(
+        ctx: Context<MoveAllocation>,
+        from: Pubkey,
+        to: Pubkey,
+        reason_code: u16,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        // Wallet and round caps are counted per wallet, so allocations only move once
+        // the sale is over
+        presale_account.require_status(&[
+            PresaleStatus::Ended,
+            PresaleStatus::Finalized,
+            PresaleStatus::Claiming,
+        ])?;
+        require!(from != to, PresaleErrors::InvalidAllocationAdjustment);
+
+        let from_user_account = &mut ctx.accounts.from_user_account;
+        let to_user_account = &mut ctx.accounts.to_user_account;
+        let previous_from_amount = from_user_account.claimable_amount();
+        let previous_to_amount = to_user_account.claimable_amount();
+        to_user_account.record_rent_payer(ctx.accounts.owner.key());
+
+        to_user_account.take_allocation_from(from_user_account)?;
+
+        msg!(
+            "Allocation of {} tokens moved from {} to {} with reason {}",
+            previous_from_amount,
+            from,
+            to,
+            reason_code
+        );
+
+        emit!(AllocationMoved {
+            from,
+            to,
+            reason_code,
+            previous_from_amount,
+            previous_to_amount,
+            new_to_amount: to_user_account.claimable_amount(),
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn change_allocation_transfers(
+        ctx: Context<UpdatePresaleState>,
+        new_transfers_disabled: bool,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+
+        msg!(
+            "Allocation transfers disabled changing from {} to {}",
+            presale_account.allocation_transfers_disabled,
+            new_transfers_disabled
+        );
+
+        presale_account.allocation_transfers_disabled = new_transfers_disabled;
+
+        Ok(())
+    }
+
+    pub fn transfer_allocation(ctx: Context<TransferAllocation>, to: Pubkey) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        // Wallet and round caps are counted per wallet, so allocations only move once
+        // the sale is over
+        presale_account.require_status(&[PresaleStatus::Ended, PresaleStatus::Finalized])?;
+        require!(
+            !presale_account.allocation_transfers_disabled,
+            PresaleErrors::AllocationTransfersDisabled
+        );
+        require!(
+            ctx.accounts.denylist_entry.data_is_empty()
+                && ctx.accounts.to_denylist_entry.data_is_empty(),
+            PresaleErrors::WalletDenylisted
+        );
+
+        let from = ctx.accounts.user.key();
+        require!(from != to, PresaleErrors::InvalidAllocationAdjustment);
+
+        let user_account = &mut ctx.accounts.user_account;
+        let amount = user_account.claimable_amount();
+        let to_user_account = &mut ctx.accounts.to_user_account;
+        to_user_account.record_rent_payer(from);
+        to_user_account.take_allocation_from(user_account)?;
+        close_user_account(user_account, &ctx.accounts.user, &ctx.accounts.rent_payer)?;
+
+        msg!(
+            "Allocation of {} tokens transferred from {} to {}",
+            amount,
+            from,
+            to
+        );
+
+        emit!(AllocationTransferred {
+            from,
+            to,
+            amount,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn start_claim(
+        ctx: Context<StartClaim>,
+        claim_start: u128,
+        tokens_to_add: u128,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Finalized])?;
+        require!(
+            presale_account.sale_token == Pubkey::default()
+                || presale_account.sale_token == ctx.accounts.sale_token.key(),
+            PresaleErrors::SaleTokenMismatch
+        );
+
+        require!(
+            presale_account.tokens_added + tokens_to_add >= presale_account.tokens_owed(),
+            PresaleErrors::IncorrectSaleTokenAdded
+        );
+
+        require!(
+            claim_start > current_time,
+            PresaleErrors::IncorrectClaimStartTime,
+        );
+
+        let decimals = ctx.accounts.sale_token.decimals;
+        require!(decimals > 0, PresaleErrors::ZeroDecimals);
+
+        presale_account.claim_start_time = claim_start;
+        presale_account.sale_token = ctx.accounts.sale_token.key();
+        presale_account.sale_token_decimals = decimals;
+        presale_account.tokens_added += tokens_to_add;
+        presale_account.whitelist_claim_only = true;
+        presale_account.status = PresaleStatus::Claiming;
+
+        let transfer_instruction = SplTransferInstruction {
+            from: ctx.accounts.owner_ata.to_account_info(),
+            to: ctx.accounts.presale_ata.to_account_info(),
+            authority: ctx.accounts.owner.to_account_info(),
+        };
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                transfer_instruction,
+            ),
+            (tokens_to_add * 10_u64.pow(decimals as u32) as u128) as u64,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn deposit_sale_tokens(ctx: Context<DepositSaleTokens>, amount: u128) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+
+        presale_account.require_status(&[
+            PresaleStatus::Pending,
+            PresaleStatus::Active,
+            PresaleStatus::Ended,
+            PresaleStatus::Finalized,
+            PresaleStatus::Claiming,
+        ])?;
+        require!(
+            !presale_account.mint_on_claim,
+            PresaleErrors::MintOnClaimEnabled
+        );
+        require!(amount > 0, PresaleErrors::InvalidAmount);
+
+        presale_account.tokens_added += amount;
+
+        let transfer_instruction = SplTransferInstruction {
+            from: ctx.accounts.owner_ata.to_account_info(),
+            to: ctx.accounts.presale_ata.to_account_info(),
+            authority: ctx.accounts.owner.to_account_info(),
+        };
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                transfer_instruction,
+            ),
+            (amount * 10_u64.pow(presale_account.sale_token_decimals as u32) as u128) as u64,
+        )?;
+
+        msg!("Sale tokens deposited are {}", amount);
+
+        emit!(SaleTokensDeposited {
+            amount,
+            tokens_added: presale_account.tokens_added,
+        });
+
+        Ok(())
+    }
+
+    pub fn change_instant_delivery(
+        ctx: Context<ChangeInstantDelivery>,
+        new_status: bool,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.sync_status(current_time);
+        // Deliveries can still be switched off mid-sale, later buyers then claim as usual
+        require!(
+            presale_account.status == PresaleStatus::Pending
+                || (!new_status && presale_account.status == PresaleStatus::Active),
+            PresaleErrors::PresaleAlreadyStarted
+        );
+
+        let decimals = ctx.accounts.sale_token.decimals;
+        require!(decimals > 0, PresaleErrors::ZeroDecimals);
+        require!(
+            presale_account.tokens_added == 0
+                || presale_account.sale_token == ctx.accounts.sale_token.key(),
+            PresaleErrors::SaleTokenMismatch
+        );
+
+        msg!(
+            "Instant delivery changing from {} to {}",
+            presale_account.instant_delivery,
+            new_status
+        );
+
+        presale_account.instant_delivery = new_status;
+        presale_account.sale_token = ctx.accounts.sale_token.key();
+        presale_account.sale_token_decimals = decimals;
+
+        Ok(())
+    }
+
+    pub fn start_claim_with_mint(
+        ctx: Context<StartClaimWithMint>,
+        claim_start: u128,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Finalized])?;
+        require!(
+            presale_account.sale_token == Pubkey::default()
+                || presale_account.sale_token == ctx.accounts.sale_token.key(),
+            PresaleErrors::SaleTokenMismatch
+        );
+
+        require!(
+            claim_start > current_time,
+            PresaleErrors::IncorrectClaimStartTime,
+        );
+
+        let decimals = ctx.accounts.sale_token.decimals;
+        require!(decimals > 0, PresaleErrors::ZeroDecimals);
+
+        presale_account.claim_start_time = claim_start;
+        presale_account.sale_token = ctx.accounts.sale_token.key();
+        presale_account.sale_token_decimals = decimals;
+        presale_account.mint_on_claim = true;
+        presale_account.whitelist_claim_only = true;
+        presale_account.status = PresaleStatus::Claiming;
+
+        msg!(
+            "Claim started with minting, mint cap is {}",
+            presale_account.mint_cap()
+        );
+
+        Ok(())
+    }
+
+    pub fn revoke_mint_authority(ctx: Context<RevokeMintAuthority>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.require_status(&[PresaleStatus::Claiming, PresaleStatus::Closed])?;
+        require!(
+            presale_account.mint_on_claim,
+            PresaleErrors::MintOnClaimDisabled
+        );
+        require!(
+            presale_account.tokens_claimed >= presale_account.mint_cap()
+                || presale_account.claim_window_closed(current_time),
+            PresaleErrors::UnclaimedTokensRemaining
+        );
+
+        token::set_authority(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                SetAuthority {
+                    current_authority: presale_account.to_account_info(),
+                    account_or_mint: ctx.accounts.sale_token.to_account_info(),
+                },
+                &[&[b"presale_account", &[ctx.bumps.presale_account]]],
+            ),
+            AuthorityType::MintTokens,
+            None,
+        )?;
+
+        emit!(MintAuthorityRevoked {
+            sale_token: presale_account.sale_token,
+            tokens_minted: presale_account.tokens_claimed,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim(ctx: Context<Claim>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        let user_account = &mut ctx.accounts.user_account;
+
+        presale_account.require_status(&[PresaleStatus::Claiming])?;
+        require!(
+            ctx.accounts.denylist_entry.data_is_empty(),
+            PresaleErrors::WalletDenylisted
+        );
+        require!(
+            !presale_account.claim_window_closed(current_time),
+            PresaleErrors::ClaimWindowClosed
+        );
+
+        if presale_account.whitelist_claim_only {
+            require!(
+                ctx.accounts.claim_whitelist_entry.is_some(),
+                PresaleErrors::NotClaimWhitelisted
+            );
+        } else {
+            require!(
+                current_time > presale_account.claim_start_time
+                    && presale_account.claim_start_time != 0,
+                PresaleErrors::ClaimNotStarted,
+            );
+        }
+
+        let claimable = user_account.claimable_amount();
+        require!(claimable > 0, PresaleErrors::NothingToClaim);
+
+        require!(!presale_account.is_paused, PresaleErrors::PresalePaused);
+
+        release_claimed_tokens(
+            presale_account,
+            &ctx.accounts.presale_ata,
+            &ctx.accounts.user_ata,
+            &ctx.accounts.sale_token,
+            &ctx.accounts.token_program,
+            ctx.bumps.presale_account,
+            claimable,
+        )?;
+        user_account.claimed_amount += claimable;
+        close_user_account(user_account, &ctx.accounts.user, &ctx.accounts.rent_payer)?;
+
+        msg!("Tokens claimed are {}", claimable);
+
+        Ok(())
+    }
+
+    pub fn increment_current_round(ctx: Context<IncrementCurrentRound>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        require!(
+            ctx.accounts.user.key() == presale_account.owner
+                || ctx.accounts.user.key() == presale_account.admin,
+            PresaleErrors::AccessRestricted,
+        );
+
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Active])?;
+
+        let new_round = presale_account.current_round + 1;
+
+        if presale_account.dynamic_time_change {
+            for i in 0..presale_account.rounds[2].len() - (new_round) as usize {
+                presale_account.rounds[2][((new_round) + i as u128) as usize] =
+                    current_time + ((i + 1) as u128 * (presale_account.per_round_time));
+            }
+        }
+
+        let sale_amount = if presale_account.current_tracker == 0 {
+            presale_account.tokens_sold
+        } else {
+            presale_account.current_tracker
+        };
+
+        presale_account.record_unsold_tokens(new_round, sale_amount);
+
+        presale_account.current_tracker = presale_account.rounds[0][(new_round - 1) as usize];
+
+        presale_account.current_round += 1;
+        presale_account.sync_status(current_time);
+
+        Ok(())
+    }
+
+    pub fn finalize(ctx: Context<Finalize>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Active])?;
+
+        let last_round = presale_account.rounds[0].len() - 1;
+        let sale_amount = if presale_account.current_tracker == 0 {
+            presale_account.tokens_sold
+        } else {
+            presale_account.current_tracker
+        };
+
+        require!(
+            current_time >= presale_account.rounds[2][last_round]
+                || sale_amount >= presale_account.rounds[0][last_round],
+            PresaleErrors::PresaleNotEnded
+        );
+
+        presale_account.end_sale(current_time);
+
+        Ok(())
+    }
+
+    pub fn change_hard_cap_usd(
+        ctx: Context<UpdatePresaleState>,
+        new_hard_cap_usd: u128,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.sync_status(current_time);
+        require!(
+            presale_account.status == PresaleStatus::Pending,
+            PresaleErrors::PresaleAlreadyStarted
+        );
+
+        msg!(
+            "Hard cap USD changing from {} to {}",
+            presale_account.hard_cap_usd,
+            new_hard_cap_usd
+        );
+
+        presale_account.hard_cap_usd = new_hard_cap_usd;
+
+        Ok(())
+    }
+
+    pub fn finalize_presale(ctx: Context<UpdatePresaleState>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Ended])?;
+
+        presale_account.status = PresaleStatus::Finalized;
+
+        emit!(PresaleFinalized {
+            tokens_sold: presale_account.tokens_sold,
+            usd_raised: presale_account.usd_raised,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn cancel_presale(ctx: Context<UpdatePresaleState>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.sync_status(current_time);
+        // Buyers have no refund path, so a sale can only be called off before it opens
+        presale_account.require_status(&[PresaleStatus::Pending])?;
+
+        let previous_status = presale_account.status;
+        presale_account.status = PresaleStatus::Cancelled;
+
+        emit!(PresaleCancelled {
+            previous_status,
+            tokens_sold: presale_account.tokens_sold,
+            usd_raised: presale_account.usd_raised,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn close_presale(ctx: Context<UpdatePresaleState>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.require_status(&[PresaleStatus::Claiming, PresaleStatus::Cancelled])?;
+
+        if presale_account.status == PresaleStatus::Claiming {
+            require!(
+                presale_account.tokens_claimed >= presale_account.tokens_owed()
+                    || presale_account.claim_window_closed(current_time),
+                PresaleErrors::UnclaimedTokensRemaining
+            );
+        }
+
+        let previous_status = presale_account.status;
+        presale_account.status = PresaleStatus::Closed;
+
+        emit!(PresaleClosed {
+            previous_status,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn add_claim_whitelist_user(
+        ctx: Context<AddClaimWhitelistUser>,
+        wallet: Pubkey,
+    ) -> Result<()> {
+        let presale_account = &ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.require_status(&[
+            PresaleStatus::Pending,
+            PresaleStatus::Active,
+            PresaleStatus::Ended,
+            PresaleStatus::Finalized,
+            PresaleStatus::Claiming,
+        ])?;
+
+        let claim_whitelist_entry = &mut ctx.accounts.claim_whitelist_entry;
+        claim_whitelist_entry.wallet = wallet;
+        claim_whitelist_entry.added_at = current_time;
+
+        msg!("Claim whitelist user added {}", wallet);
+
+        emit!(ClaimWhitelistUpdated {
+            wallet,
+            whitelisted: true,
+        });
+
+        Ok(())
+    }
+
+    pub fn remove_claim_whitelist_user(
+        ctx: Context<RemoveClaimWhitelistUser>,
+        wallet: Pubkey,
+    ) -> Result<()> {
+        msg!(
+            "Claim whitelist user removed {}",
+            ctx.accounts.claim_whitelist_entry.wallet
+        );
+
+        emit!(ClaimWhitelistUpdated {
+            wallet,
+            whitelisted: false,
+        });
+
+        Ok(())
+    }
+
+    pub fn create_discount_code(
+        ctx: Context<CreateDiscountCode>,
+        code_hash: [u8; 32],
+        discount_bps: u16,
+        max_redemptions: u128,
+        max_redemptions_per_wallet: u128,
+        expiry: u128,
+    ) -> Result<()> {
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        require!(
+            discount_bps <= 10_000 && expiry > current_time,
+            PresaleErrors::InvalidDiscountCode
+        );
+
+        let discount_code = &mut ctx.accounts.discount_code;
+        discount_code.code_hash = code_hash;
+        discount_code.discount_bps = discount_bps;
+        discount_code.max_redemptions = max_redemptions;
+        discount_code.max_redemptions_per_wallet = max_redemptions_per_wallet;
+        discount_code.expiry = expiry;
+
+        msg!(
+            "Discount code created with {} bps until {}",
+            discount_bps,
+            expiry
+        );
+
+        Ok(())
+    }
+
+    pub fn close_discount_code(ctx: Context<CloseDiscountCode>, code_hash: [u8; 32]) -> Result<()> {
+        msg!(
+            "Discount code {:?} closed after {} redemptions",
+            code_hash,
+            ctx.accounts.discount_code.redemptions
+        );
+
+        Ok(())
+    }
+
+    pub fn add_denylist_user(
+        ctx: Context<AddDenylistUser>,
+        wallet: Pubkey,
+        reason_code: u16,
+    ) -> Result<()> {
+        let presale_account = &ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        require!(
+            ctx.accounts.authority.key() == presale_account.owner
+                || ctx.accounts.authority.key() == presale_account.admin,
+            PresaleErrors::AccessRestricted,
+        );
+
+        let denylist_entry = &mut ctx.accounts.denylist_entry;
+        denylist_entry.wallet = wallet;
+        denylist_entry.reason_code = reason_code;
+        denylist_entry.added_at = current_time;
+
+        msg!("Denylisted user {} with reason {}", wallet, reason_code);
+
+        emit!(DenylistUpdated {
+            wallet,
+            reason_code,
+            denylisted: true,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn release_denylist_user(ctx: Context<ReleaseDenylistUser>, wallet: Pubkey) -> Result<()> {
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        let reason_code = ctx.accounts.denylist_entry.reason_code;
+
+        msg!("Released denylisted user {}", wallet);
+
+        emit!(DenylistUpdated {
+            wallet,
+            reason_code,
+            denylisted: false,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn reclaim_denylisted_allocation(
+        ctx: Context<ReclaimDenylistedAllocation>,
+        wallet: Pubkey,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let user_account = &mut ctx.accounts.user_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        // Allocations are only final once the sale is over
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[
+            PresaleStatus::Ended,
+            PresaleStatus::Finalized,
+            PresaleStatus::Claiming,
+        ])?;
+
+        let amount = user_account.claimable_amount();
+        require!(amount > 0, PresaleErrors::NothingToClaim);
+
+        // Purchase history stays so the wallet caps still apply
+        user_account.reclaimed_amount += amount;
+        presale_account.tokens_reclaimed += amount;
+
+        msg!(
+            "Reclaimed {} tokens from denylisted user {}",
+            amount,
+            wallet
+        );
+
+        emit!(DenylistedAllocationReclaimed {
+            wallet,
+            amount,
+            reason_code: ctx.accounts.denylist_entry.reason_code,
+            timestamp: current_time,
+        });
+
+        Ok(())
+    }
+
+    pub fn change_unsold_policy(
+        ctx: Context<UpdatePresaleState>,
+        new_policy: UnsoldPolicy,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Pending, PresaleStatus::Active])?;
+
+        msg!(
+            "Unsold policy changing from {:?} to {:?}",
+            presale_account.unsold_policy,
+            new_policy
+        );
+
+        presale_account.unsold_policy = new_policy;
+
+        Ok(())
+    }
+
+    pub fn rollover_unsold_tokens(ctx: Context<UpdatePresaleState>, round: u128) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+        presale_account.sync_status(current_time);
+        presale_account.require_status(&[PresaleStatus::Active])?;
+
+        require!(
+            presale_account.unsold_policy == UnsoldPolicy::Rollover,
+            PresaleErrors::InvalidUnsoldPolicy
+        );
+        require!(
+            round < presale_account.unsold_tokens.len() as u128,
+            PresaleErrors::NoUnsoldTokens
+        );
+
+        let amount = presale_account.unsold_tokens[round as usize];
+        require!(amount > 0, PresaleErrors::NoUnsoldTokens);
+
+        // Lowering the tracker opens up `amount` more tokens in the current round, which
+        // carries forward through the cumulative caps of the following rounds.
+        require!(
+            presale_account.current_tracker >= presale_account.tokens_sold + amount,
+            PresaleErrors::NoUnsoldTokens
+        );
+
+        presale_account.current_tracker -= amount;
+        presale_account.unsold_tokens[round as usize] = 0;
+
+        emit!(UnsoldTokensRolledOver {
+            round,
+            into_round: presale_account.current_round,
+            amount,
+            current_tracker: presale_account.current_tracker,
+        });
+
+        Ok(())
+    }
+
+    pub fn burn_unsold_tokens(ctx: Context<BurnUnsoldTokens>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+
+        presale_account.require_status(&[
+            PresaleStatus::Finalized,
+            PresaleStatus::Claiming,
+            PresaleStatus::Closed,
+        ])?;
+        require!(
+            presale_account.unsold_policy == UnsoldPolicy::Burn,
+            PresaleErrors::InvalidUnsoldPolicy
+        );
+
+        let amount = presale_account.take_unsold_tokens()?;
+
+        token::burn(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Burn {
+                    mint: ctx.accounts.sale_token.to_account_info(),
+                    from: ctx.accounts.presale_ata.to_account_info(),
+                    authority: presale_account.to_account_info(),
+                },
+                &[&[b"presale_account", &[ctx.bumps.presale_account]]],
+            ),
+            (amount * 10_u64.pow(presale_account.sale_token_decimals as u32) as u128) as u64,
+        )?;
+
+        msg!("Unsold tokens burned are {}", amount);
+
+        emit!(UnsoldTokensBurned {
+            amount,
+            tokens_added: presale_account.tokens_added,
+        });
+
+        Ok(())
+    }
+
+    pub fn withdraw_unsold_tokens(ctx: Context<WithdrawSaleTokens>) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+
+        presale_account.require_status(&[
+            PresaleStatus::Finalized,
+            PresaleStatus::Claiming,
+            PresaleStatus::Closed,
+        ])?;
+        require!(
+            presale_account.unsold_policy == UnsoldPolicy::ReturnToOwner,
+            PresaleErrors::InvalidUnsoldPolicy
+        );
+
+        let amount = presale_account.take_unsold_tokens()?;
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                SplTransferInstruction {
+                    from: ctx.accounts.presale_ata.to_account_info(),
+                    to: ctx.accounts.owner_ata.to_account_info(),
+                    authority: presale_account.to_account_info(),
+                },
+                &[&[b"presale_account", &[ctx.bumps.presale_account]]],
+            ),
+            (amount * 10_u64.pow(presale_account.sale_token_decimals as u32) as u128) as u64,
+        )?;
+
+        msg!("Unsold tokens withdrawn are {}", amount);
+
+        emit!(UnsoldTokensWithdrawn {
+            amount,
+            to: ctx.accounts.owner_ata.key(),
+            tokens_added: presale_account.tokens_added,
+        });
+
+        Ok(())
+    }
+
+    pub fn change_claim_deadline(
+        ctx: Context<UpdatePresaleState>,
+        new_claim_deadline: u128,
+    ) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        presale_account.require_status(&[PresaleStatus::Claiming])?;
+        require!(
+            !presale_account.claim_window_closed(current_time),
+            PresaleErrors::ClaimWindowClosed
+        );
+        require!(
+            new_claim_deadline > current_time
+                && new_claim_deadline > presale_account.claim_start_time,
+            PresaleErrors::InvalidClaimDeadline
+        );
+        // Buyers may rely on the announced window, so it can only be extended
+        require!(
+            presale_account.claim_deadline == 0
+                || new_claim_deadline >= presale_account.claim_deadline,
+            PresaleErrors::InvalidClaimDeadline
+        );
+
+        msg!(
+            "Claim deadline changing from {} to {}",
+            presale_account.claim_deadline,
+            new_claim_deadline
+        );
+
+        presale_account.claim_deadline = new_claim_deadline;
+
+        Ok(())
+    }
+
+    pub fn withdraw_surplus_tokens(ctx: Context<WithdrawSaleTokens>, amount: u128) -> Result<()> {
+        let presale_account = &mut ctx.accounts.presale_account;
+        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+
+        require!(amount > 0, PresaleErrors::InvalidAmount);
+        require!(
+            amount <= presale_account.withdrawable_surplus(current_time),
+            PresaleErrors::InsufficientSurplusTokens
+        );
+
+        presale_account.tokens_added -= amount;
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                SplTransferInstruction {
+                    from: ctx.accounts.presale_ata.to_account_info(),
+                    to: ctx.accounts.owner_ata.to_account_info(),
+                    authority: presale_account.to_account_info(),
+                },
+                &[&[b"presale_account", &[ctx.bumps.presale_account]]],
+            ),
+            (amount * 10_u64.pow(presale_account.sale_token_decimals as u32) as u128) as u64,
+        )?;
+
+        msg!("Surplus tokens withdrawn are {}", amount);
+
+        emit!(SurplusTokensWithdrawn {
+            amount,
+            to: ctx.accounts.owner_ata.key(),
+            includes_unclaimed: presale_account.claim_window_closed(current_time),
+            tokens_added: presale_account.tokens_added,
+        });
+
+        Ok(())
+    }
+}
+
+/// Runs the checks and round accounting shared by every buy path and credits the
+/// purchase to `user_account`. Payment is left to the caller.
+fn process_purchase(
+    presale_account: &mut PresaleAccount,
+    user_account: &mut UserAccount,
+    order: PurchaseOrder,
+    current_time: u128,
+) -> Result<(CalculateReturn, u128, u128)> {
+    let PurchaseOrder {
+        payer,
+        beneficiary,
+        amount,
+        allow_partial,
+        allowlist_proof,
+        discount,
+    } = order;
+    let discount_bps = discount
+        .as_ref()
+        .map_or(0, |discount| discount.discount_bps);
+
+    require!(
+        current_time > presale_account.start_time,
+        PresaleErrors::PresaleNotStarted
+    );
+    presale_account.sync_status(current_time);
+    require!(
+        presale_account.status == PresaleStatus::Active,
+        PresaleErrors::InvalidPresaleStatus
+    );
+    require!(amount > 0, PresaleErrors::InvalidBuyAmount);
+    require!(!presale_account.is_paused, PresaleErrors::PresalePaused);
+
+    let requested_amount = amount;
+    let mut amount = amount;
+    if allow_partial {
+        amount = max_fillable_amount(presale_account, amount, u128::MAX, discount_bps);
+        if amount == 0 {
+            // Nothing is left to fill, surface why the requested amount is rejected
+            calculate_price_internal(presale_account, requested_amount)?;
+        }
+    }
+
+    let mut price_and_round = calculate_discounted_price(presale_account, amount, discount_bps)?;
+    require!(price_and_round.price_in_usd > 0, PresaleErrors::ZeroPrice);
+
+    if presale_account.hard_cap_usd != 0
+        && presale_account.usd_raised + price_and_round.price_in_usd > presale_account.hard_cap_usd
+    {
+        // Final fill that stops at the hard cap
+        let budget_usd = presale_account.hard_cap_usd - presale_account.usd_raised;
+        amount = max_fillable_amount(presale_account, amount, budget_usd, discount_bps);
+        require!(amount > 0, PresaleErrors::HardCapReached);
+        price_and_round = calculate_discounted_price(presale_account, amount, discount_bps)?;
+    }
+
+    // A trimmed final fill may be smaller than the round minimum
+    require!(
+        amount < requested_amount
+            || price_and_round.price_in_usd
+                >= presale_account.min_purchase_usd_for(price_and_round.new_round),
+        PresaleErrors::BelowMinPurchase
+    );
+
+    let allowlist_root = presale_account.merkle_root_for(price_and_round.new_round);
+    if allowlist_root != [0; 32] {
+        let allowlist_proof = allowlist_proof.ok_or(PresaleErrors::NotAllowlisted)?;
+        let leaf = keccak::hashv(&[
+            beneficiary.as_ref(),
+            &allowlist_proof.allocation.to_le_bytes(),
+        ])
+        .0;
+        require!(
+            verify_merkle_proof(&allowlist_proof.proof, allowlist_root, leaf),
+            PresaleErrors::NotAllowlisted
+        );
+        require!(
+            allowlist_proof.allocation == 0
+                || user_account.round_purchased_amount(price_and_round.new_round) + amount
+                    <= allowlist_proof.allocation,
+            PresaleErrors::AllowlistAllocationExceeded
+        );
+    }
+
+    require_wallet_allowance(
+        presale_account,
+        user_account,
+        price_and_round.new_round,
+        amount,
+        current_time,
+    )?;
+
+    if let Some(discount) = discount {
+        let discount_usd = calculate_price_internal(presale_account, amount)?.price_in_usd
+            - price_and_round.price_in_usd;
+
+        msg!("Discount code applied, USD {} off", discount_usd);
+
+        emit!(DiscountCodeRedeemed {
+            code_hash: discount.code_hash,
+            buyer: beneficiary,
+            discount_usd,
+            price_in_usd: price_and_round.price_in_usd,
+            timestamp: current_time,
+        });
+    }
+
+    record_purchase(
+        presale_account,
+        user_account,
+        &price_and_round,
+        amount,
+        current_time,
+    );
+
+    let bonus_amount =
+        amount * presale_account.bonus_bps_for(price_and_round.price_in_usd) as u128 / 10_000;
+    if bonus_amount > 0 {
+        user_account.bonus_amount += bonus_amount;
+        presale_account.bonus_tokens_allocated += bonus_amount;
+        msg!("Bonus tokens allocated are {}", bonus_amount);
+    }
+
+    let early_bird_bonus_amount = amount
+        * presale_account.early_bird_bonus_bps_at(price_and_round.new_round, current_time) as u128
+        / 10_000;
+    if early_bird_bonus_amount > 0 {
+        user_account.early_bird_bonus_amount += early_bird_bonus_amount;
+        presale_account.early_bird_tokens_allocated += early_bird_bonus_amount;
+        msg!(
+            "Early bird bonus tokens allocated are {}",
+            early_bird_bonus_amount
+        );
+    }
+
+    emit!(TokensPurchased {
+        payer,
+        beneficiary,
+        amount,
+        requested_amount,
+        bonus_amount,
+        early_bird_bonus_amount,
+        price_in_usd: price_and_round.price_in_usd,
+        round: price_and_round.new_round,
+        timestamp: current_time,
+    });
+
+    if presale_account.hard_cap_reached() {
+        presale_account.end_sale(current_time);
+    }
+
+    Ok((
+        price_and_round,
+        amount,
+        bonus_amount + early_bird_bonus_amount,
+    ))
+}
+
+/// Moves the rounds forward for a filled purchase and credits it to the sale and the
+/// buyer.
+fn record_purchase(
+    presale_account: &mut PresaleAccount,
+    user_account: &mut UserAccount,
+    price_and_round: &CalculateReturn,
+    amount: u128,
+    current_time: u128,
+) {
+    let previous_round = presale_account.current_round;
+    let sellout_fill =
+        presale_account.sellout_fill(price_and_round.new_round, amount, current_time);
+
+    if price_and_round.new_round != presale_account.current_round {
+        // Update all rounds from now with the new time
+        if presale_account.dynamic_time_change {
+            for i in 0..presale_account.rounds[2].len() - price_and_round.new_round as usize {
+                presale_account.rounds[2][(price_and_round.new_round + i as u128) as usize] =
+                    current_time + ((i + 1) as u128 * (presale_account.per_round_time));
+            }
+        }
+
+        // Update unsold tokens tracker
+        let sale_amount = if presale_account.current_tracker == 0 {
+            presale_account.tokens_sold + amount
+        } else {
+            presale_account.current_tracker + amount
+        };
+
+        presale_account.record_unsold_tokens(price_and_round.new_round, sale_amount);
+
+        if current_time >= presale_account.rounds[2][presale_account.current_round as usize] {
+            presale_account.current_tracker =
+                presale_account.rounds[0][(price_and_round.new_round - 1) as usize];
+        }
+
+        presale_account.current_round = price_and_round.new_round;
+    }
+
+    presale_account.usd_raised += price_and_round.price_in_usd;
+    presale_account.tokens_sold += amount;
+
+    if presale_account.current_tracker != 0 {
+        presale_account.current_tracker += amount;
+    }
+
+    // A buy selling out a round counts towards both rounds' caps
+    user_account.purchased_amount += amount;
+    user_account.round_purchased[previous_round as usize] += sellout_fill;
+    user_account.round_purchased[price_and_round.new_round as usize] += amount - sellout_fill;
+}
+
+/// Checks a purchase against the wallet caps, counting the part that sells out the
+/// current round against that round rather than the next.
+fn require_wallet_allowance(
+    presale_account: &PresaleAccount,
+    user_account: &UserAccount,
+    new_round: u128,
+    amount: u128,
+    current_time: u128,
+) -> Result<()> {
+    let sellout_fill = presale_account.sellout_fill(new_round, amount, current_time);
+
+    require!(
+        sellout_fill
+            <= presale_account.wallet_allowance(
+                user_account.purchased_amount,
+                user_account.round_purchased_amount(presale_account.current_round),
+            )
+            && amount - sellout_fill
+                <= presale_account.wallet_allowance(
+                    user_account.purchased_amount + sellout_fill,
+                    user_account.round_purchased_amount(new_round),
+                ),
+        PresaleErrors::WalletCapExceeded
+    );
+
+    Ok(())
+}
+
+/// Bonus granted on `previous_amount` purchased tokens, rescaled to `new_amount`.
+fn scale_bonus(bonus: u128, new_amount: u128, previous_amount: u128) -> Result<u128> {
+    if previous_amount == 0 {
+        return Ok(0);
+    }
+
+    Ok(bonus
+        .checked_mul(new_amount)
+        .ok_or(PresaleErrors::MathOverflow)?
+        / previous_amount)
+}
+
+/// Rounds are three rows (token caps, prices, end times) of one to `MAX_ROUNDS` entries.
+fn require_valid_rounds(rounds: &[Vec<u128>]) -> Result<()> {
+    require!(
+        rounds.len() == 3
+            && (1..=MAX_ROUNDS).contains(&rounds[0].len())
+            && rounds.iter().all(|row| row.len() == rounds[0].len()),
+        PresaleErrors::InvalidLength
+    );
+
+    Ok(())
+}
+
+/// Requires the instruction right before the purchase to be an ed25519 program check of
+/// the attester's signature over (buyer, presale, expiry, tier) and returns the tier.
+/// Returns `None` when no attester is configured.
+fn verify_kyc_attestation(
+    presale_account: &PresaleAccount,
+    presale: Pubkey,
+    instructions: &Option<UncheckedAccount>,
+    buyer: Pubkey,
+    current_time: u128,
+) -> Result<Option<u8>> {
+    if presale_account.kyc_attester == Pubkey::default() {
+        return Ok(None);
+    }
+
+    let instructions = instructions
+        .as_ref()
+        .ok_or(PresaleErrors::MissingKycAttestation)?;
+    let current_index = load_current_index_checked(instructions)?;
+    require!(current_index > 0, PresaleErrors::MissingKycAttestation);
+    let ed25519_ix = load_instruction_at_checked((current_index - 1) as usize, instructions)?;
+    require!(
+        ed25519_ix.program_id == ed25519_program::ID,
+        PresaleErrors::MissingKycAttestation
+    );
+
+    // Single signature with offsets header, all data inside the ed25519 instruction itself
+    let data = &ed25519_ix.data;
+    require!(
+        data.len() >= 16 && data[0] == 1,
+        PresaleErrors::InvalidKycAttestation
+    );
+    let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+    let public_key_offset = read_u16(6);
+    let message_offset = read_u16(10);
+    let message_size = read_u16(12);
+    require!(
+        [read_u16(4), read_u16(8), read_u16(14)] == [u16::MAX as usize; 3]
+            && message_size == KYC_MESSAGE_LEN
+            && data.len() >= public_key_offset + 32
+            && data.len() >= message_offset + KYC_MESSAGE_LEN,
+        PresaleErrors::InvalidKycAttestation
+    );
+
+    let message = &data[message_offset..message_offset + KYC_MESSAGE_LEN];
+    require!(
+        data[public_key_offset..public_key_offset + 32] == presale_account.kyc_attester.to_bytes()
+            && message[..32] == buyer.to_bytes()
+            && message[32..64] == presale.to_bytes(),
+        PresaleErrors::InvalidKycAttestation
+    );
+
+    let expiry = u128::from_le_bytes(message[64..80].try_into().unwrap());
+    require!(expiry > current_time, PresaleErrors::KycAttestationExpired);
+
+    let tier = message[80];
+    msg!("KYC attestation verified for {} with tier {}", buyer, tier);
+
+    Ok(Some(tier))
+}
+
+/// Checks a proof built with sorted pairs of keccak hashes.
+fn verify_merkle_proof(proof: &[[u8; 32]], root: [u8; 32], leaf: [u8; 32]) -> bool {
+    let computed = proof.iter().fold(leaf, |hash, node| {
+        if hash <= *node {
+            keccak::hashv(&[&hash, node]).0
+        } else {
+            keccak::hashv(&[node, &hash]).0
+        }
+    });
+
+    computed == root
+}
+
+/// Largest amount, up to `amount`, that the remaining rounds can fill for at most
+/// `budget_usd` once `discount_bps` is taken off.
+fn max_fillable_amount(
+    presale_account: &PresaleAccount,
+    amount: u128,
+    budget_usd: u128,
+    discount_bps: u16,
+) -> u128 {
+    let mut low = 0;
+    let mut high = amount;
+
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        let fits = calculate_discounted_price(presale_account, mid, discount_bps)
+            .is_ok_and(|price_and_round| price_and_round.price_in_usd <= budget_usd);
+        if fits {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    low
+}
+
+/// Creates the program-owned PDA `account`, the way `init` does: a PDA that already
+/// holds lamports can't go through `create_account`, so it is topped up to rent
+/// exemption, allocated and assigned instead.
+fn create_program_account<'info>(
+    payer: &Signer<'info>,
+    account: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    space: usize,
+    seeds: &[&[u8]],
+    owner: &Pubkey,
+) -> Result<()> {
+    let rent = Rent::get()?.minimum_balance(space);
+    let lamports = account.lamports();
+
+    if lamports == 0 {
+        return create_account(
+            CpiContext::new_with_signer(
+                system_program.to_account_info(),
+                CreateAccount {
+                    from: payer.to_account_info(),
+                    to: account.clone(),
+                },
+                &[seeds],
+            ),
+            rent,
+            space as u64,
+            owner,
+        );
+    }
+
+    if lamports < rent {
+        transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                Transfer {
+                    from: payer.to_account_info(),
+                    to: account.clone(),
+                },
+            ),
+            rent - lamports,
+        )?;
+    }
+    allocate(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            Allocate {
+                account_to_allocate: account.clone(),
+            },
+            &[seeds],
+        ),
+        space as u64,
+    )?;
+    assign(
+        CpiContext::new_with_signer(
+            system_program.to_account_info(),
+            Assign {
+                account_to_assign: account.clone(),
+            },
+            &[seeds],
+        ),
+        owner,
+    )
+}
+
+/// Mints or transfers claimed sale tokens to `to`, depending on the claim mode.
+fn release_claimed_tokens<'info>(
+    presale_account: &mut Account<'info, PresaleAccount>,
+    presale_ata: &Option<Account<'info, TokenAccount>>,
+    to: &Account<'info, TokenAccount>,
+    sale_token: &Account<'info, Mint>,
+    token_program: &Program<'info, Token>,
+    presale_bump: u8,
+    amount: u128,
+) -> Result<()> {
+    if presale_account.mint_on_claim {
+        require!(
+            presale_account.tokens_claimed + amount <= presale_account.mint_cap(),
+            PresaleErrors::MintCapExceeded
+        );
+
+        let mint_instruction = MintTo {
+            mint: sale_token.to_account_info(),
+            to: to.to_account_info(),
+            authority: presale_account.to_account_info(),
+        };
+
+        token::mint_to(
+            CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                mint_instruction,
+                &[&[b"presale_account", &[presale_bump]]],
+            ),
+            amount as u64 * (10_u64.pow(presale_account.sale_token_decimals as u32)),
+        )?;
+    } else {
+        require!(
+            presale_account.available_sale_tokens() >= amount,
+            PresaleErrors::InsufficientSaleTokenLiquidity
+        );
+
+        let presale_ata = presale_ata
+            .as_ref()
+            .ok_or(PresaleErrors::MissingOptionalAccount)?;
+
+        let transfer_instruction = SplTransferInstruction {
+            from: presale_ata.to_account_info(),
+            to: to.to_account_info(),
+            authority: presale_account.to_account_info(),
+        };
+
+        token::transfer(
             CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
+                token_program.to_account_info(),
                 transfer_instruction,
-                &[&[b"presale_account", &[ctx.bumps.presale_account]]],
+                &[&[b"presale_account", &[presale_bump]]],
             ),
-            user_account.purchased_amount as u64
-                * (10_u64.pow(presale_account.sale_token_decimals as u32)) as u64,
+            amount as u64 * (10_u64.pow(presale_account.sale_token_decimals as u32)) as u64,
         )?;
+    }
 
-        presale_account.tokens_claimed += user_account.purchased_amount;
+    presale_account.tokens_claimed += amount;
 
-        msg!("Tokens claimed are {}", user_account.purchased_amount);
+    Ok(())
+}
 
-        Ok(())
-    }
+/// Closes a user account and refunds its rent to the wallet that paid for it.
+fn close_user_account<'info>(
+    user_account: &mut Account<'info, UserAccount>,
+    user: &Signer<'info>,
+    rent_payer: &Option<UncheckedAccount<'info>>,
+) -> Result<()> {
+    let destination = match rent_payer {
+        Some(rent_payer) => rent_payer.to_account_info(),
+        None => {
+            require_keys_eq!(
+                user_account.rent_payer,
+                user.key(),
+                PresaleErrors::MissingOptionalAccount
+            );
+            user.to_account_info()
+        }
+    };
 
-    pub fn increment_current_round(ctx: Context<IncrementCurrentRound>) -> Result<()> {
-        let presale_account = &mut ctx.accounts.presale_account;
-        let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
+    user_account.close(destination)
+}
 
-        require!(
-            ctx.accounts.user.key() == presale_account.owner
-                || ctx.accounts.user.key() == presale_account.admin,
-            PresaleErrors::AccessRestricted,
-        );
+/// Records a referred purchase and credits the referrer. Sale token rewards are added to
+/// the referrer's claimable balance, otherwise the returned basis points of the payment
+/// go to the referrer.
+fn credit_referral(
+    presale_account: &mut PresaleAccount,
+    referrer_account: &mut ReferrerAccount,
+    payer: Pubkey,
+    buyer: Pubkey,
+    amount: u128,
+    price_in_usd: u128,
+    current_time: u128,
+) -> Result<u128> {
+    require!(
+        referrer_account.referrer != payer && referrer_account.referrer != buyer,
+        PresaleErrors::InvalidReferrer
+    );
 
-        let new_round = presale_account.current_round + 1;
+    let reward_bps = presale_account.referral_reward_bps as u128;
+    let reward_usd = price_in_usd * reward_bps / 10_000;
+    let mut reward_tokens = 0;
 
-        if presale_account.dynamic_time_change {
-            for i in 0..presale_account.rounds[2].len() - (new_round) as usize {
-                presale_account.rounds[2][((new_round) + i as u128) as usize] =
-                    current_time + ((i + 1) as u128 * (presale_account.per_round_time));
-            }
+    referrer_account.referred_count += 1;
+    referrer_account.referred_usd += price_in_usd;
+    referrer_account.reward_usd += reward_usd;
+
+    let referral_payment_bps = match presale_account.referral_reward_mode {
+        ReferralRewardMode::SaleToken => {
+            reward_tokens = amount * reward_bps / 10_000;
+            referrer_account.reward_tokens += reward_tokens;
+            presale_account.referral_tokens_allocated += reward_tokens;
+            0
         }
+        ReferralRewardMode::PaymentCurrency => reward_bps,
+    };
 
-        let sale_amount = if presale_account.current_tracker == 0 {
-            presale_account.tokens_sold
-        } else {
-            presale_account.current_tracker
-        };
+    msg!(
+        "Referral by {} rewarded USD {} and {} tokens",
+        referrer_account.referrer,
+        reward_usd,
+        reward_tokens
+    );
 
-        let value =
-            if sale_amount > presale_account.rounds[0][presale_account.current_round as usize] {
-                0
-            } else {
-                presale_account.rounds[0][presale_account.current_round as usize] - sale_amount
-            };
-        presale_account.unsold_tokens.push(value);
+    emit!(ReferralRewarded {
+        referrer: referrer_account.referrer,
+        buyer,
+        mode: presale_account.referral_reward_mode,
+        reward_usd,
+        reward_tokens,
+        timestamp: current_time,
+    });
 
-        presale_account.current_tracker = presale_account.rounds[0][(new_round - 1) as usize];
+    Ok(referral_payment_bps)
+}
 
-        presale_account.current_round += 1;
+/// Checks the code limits and records the redemption. The discount itself is applied
+/// to the purchase price by `process_purchase`.
+fn redeem_discount_code(
+    discount_code: &mut DiscountCode,
+    discount_redemption: &mut DiscountRedemption,
+    current_time: u128,
+) -> Result<PurchaseDiscount> {
+    require!(
+        current_time < discount_code.expiry,
+        PresaleErrors::DiscountCodeExpired
+    );
+    require!(
+        discount_code.max_redemptions == 0
+            || discount_code.redemptions < discount_code.max_redemptions,
+        PresaleErrors::DiscountCodeExhausted
+    );
+    require!(
+        discount_code.max_redemptions_per_wallet == 0
+            || discount_redemption.redemptions < discount_code.max_redemptions_per_wallet,
+        PresaleErrors::DiscountCodeWalletLimit
+    );
 
-        Ok(())
-    }
+    discount_code.redemptions += 1;
+    discount_redemption.redemptions += 1;
 
-    pub fn change_claim_whitelist_users(
-        ctx: Context<UpdatePresaleState>,
-        new_wallets: Vec<Pubkey>,
-    ) -> Result<()> {
-        require!(new_wallets.len() == 5, PresaleErrors::InvalidLength);
+    Ok(PurchaseDiscount {
+        code_hash: discount_code.code_hash,
+        discount_bps: discount_code.discount_bps,
+    })
+}
 
-        let presale_account = &mut ctx.accounts.presale_account;
+/// Transfers purchased tokens straight from the prefunded presale ATA to the buyer and
+/// marks them as claimed, so the `UserAccount` only keeps the purchase history.
+fn deliver_sale_tokens<'info>(
+    presale_account: &mut Account<'info, PresaleAccount>,
+    user_account: &mut UserAccount,
+    presale_ata: &Option<Account<'info, TokenAccount>>,
+    user_sale_ata: &Option<Account<'info, TokenAccount>>,
+    token_program: &Option<Program<'info, Token>>,
+    presale_bump: u8,
+    amount: u128,
+) -> Result<()> {
+    let (Some(presale_ata), Some(user_sale_ata), Some(token_program)) =
+        (presale_ata, user_sale_ata, token_program)
+    else {
+        return err!(PresaleErrors::MissingOptionalAccount);
+    };
+
+    require!(
+        presale_account.available_sale_tokens() >= amount,
+        PresaleErrors::InsufficientSaleTokenLiquidity
+    );
 
-        presale_account.whitelisted_users = new_wallets;
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            SplTransferInstruction {
+                from: presale_ata.to_account_info(),
+                to: user_sale_ata.to_account_info(),
+                authority: presale_account.to_account_info(),
+            },
+            &[&[b"presale_account", &[presale_bump]]],
+        ),
+        (amount * 10_u64.pow(presale_account.sale_token_decimals as u32) as u128) as u64,
+    )?;
 
-        Ok(())
-    }
+    presale_account.tokens_claimed += amount;
+    user_account.claimed_amount += amount;
+
+    msg!("Tokens delivered are {}", amount);
+
+    Ok(())
+}
+
+/// Price of `amount` tokens with `discount_bps` taken off the USD price.
+fn calculate_discounted_price(
+    presale_account: &PresaleAccount,
+    amount: u128,
+    discount_bps: u16,
+) -> Result<CalculateReturn> {
+    let mut price_and_round = calculate_price_internal(presale_account, amount)?;
+    price_and_round.price_in_usd -= price_and_round.price_in_usd * discount_bps as u128 / 10_000;
+
+    Ok(price_and_round)
 }
 
 fn calculate_price_internal(
@@ -612,6 +2619,12 @@ fn calculate_price_internal(
 ) -> Result<CalculateReturn> {
     let current_time: u128 = Clock::get()?.unix_timestamp.try_into().unwrap();
 
+    require!(
+        presale_account.status == PresaleStatus::Pending
+            || presale_account.status == PresaleStatus::Active,
+        PresaleErrors::PresaleEnded
+    );
+
     require!(
         amount <= presale_account.max_tokens_to_buy,
         PresaleErrors::InvalidAmount
@@ -708,73 +2721,424 @@ pub struct CalculatePriceSOL<'info> {
 }
 
 #[derive(Accounts)]
-pub struct BuyWithSol<'info> {
+pub struct RemainingAllowance<'info> {
+    /// CHECK: Only used to derive the user account PDA
+    pub user: UncheckedAccount<'info>,
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(seeds = [b"user_account", user.key().as_ref()], bump)]
+    pub user_account: Option<Account<'info, UserAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u128, allow_partial: bool, allowlist_proof: Option<AllowlistProof>, beneficiary: Option<Pubkey>)]
+pub struct BuyWithSol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    /// Pays the rent for new accounts, falls back to `user` when not sponsored
+    #[account(mut)]
+    pub fee_payer: Option<Signer<'info>>,
+    #[account(mut, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(init_if_needed, payer = fee_payer.as_ref().unwrap_or(&user), space = 8 + UserAccount::INIT_SPACE, seeds = [(b"user_account"), beneficiary.unwrap_or(user.key()).as_ref()], bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(seeds = [b"denylist", user.key().as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the wallet is denylisted
+    pub denylist_entry: UncheckedAccount<'info>,
+    #[account(seeds = [b"denylist", beneficiary.unwrap_or(user.key()).as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the beneficiary is denylisted
+    pub beneficiary_denylist_entry: UncheckedAccount<'info>,
+    #[account(mut)]
+    /// CHECK: To pass payment wallet as account info
+    pub payment_wallet: UncheckedAccount<'info>,
+    #[account(address = Pubkey::from_str(CHAINLINK_PROGRAM).unwrap())]
+    /// CHECK: This is safe as we are using chainlink program
+    pub chainlink_program: UncheckedAccount<'info>,
+    #[account(address = Pubkey::from_str(CHAINLINK_FEED).unwrap())]
+    /// CHECK: This is safe as we are using chainlink feed
+    pub chainlink_feed: UncheckedAccount<'info>,
+    #[account(mut, associated_token::mint = sale_token, associated_token::authority = presale_account)]
+    pub presale_ata: Option<Account<'info, TokenAccount>>,
+    #[account(address = beneficiary.unwrap_or(user.key()))]
+    /// CHECK: Owner of the delivery ATA, only needed when buying for another wallet
+    pub beneficiary_wallet: Option<UncheckedAccount<'info>>,
+    #[account(init_if_needed, payer = fee_payer.as_ref().unwrap_or(&user), associated_token::mint = sale_token, associated_token::authority = beneficiary_wallet.as_ref().map_or(user.to_account_info(), |wallet| wallet.to_account_info()))]
+    pub user_sale_ata: Option<Account<'info, TokenAccount>>,
+    #[account(address = presale_account.sale_token)]
+    pub sale_token: Option<Account<'info, Mint>>,
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+    pub token_program: Option<Program<'info, Token>>,
+    pub system_program: Program<'info, System>,
+    #[account(mut)]
+    pub referrer_account: Option<Account<'info, ReferrerAccount>>,
+    #[account(mut)]
+    /// CHECK: Receives SOL referral rewards, checked against the referrer account
+    pub referrer: Option<UncheckedAccount<'info>>,
+    #[account(mut)]
+    pub discount_code: Option<Account<'info, DiscountCode>>,
+    #[account(init_if_needed, payer = fee_payer.as_ref().unwrap_or(&user), space = 8 + DiscountRedemption::INIT_SPACE, seeds = [b"discount_redemption", discount_code.as_ref().map(|code| code.key()).unwrap_or_default().as_ref(), beneficiary.unwrap_or(user.key()).as_ref()], bump)]
+    pub discount_redemption: Option<Account<'info, DiscountRedemption>>,
+    #[account(address = sysvar::instructions::ID)]
+    /// CHECK: Instructions sysvar, only read to find the KYC attestation
+    pub instructions: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u128, allow_partial: bool, allowlist_proof: Option<AllowlistProof>, beneficiary: Option<Pubkey>)]
+pub struct BuyWithUSDT<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    /// Pays the rent for new accounts, falls back to `user` when not sponsored
+    #[account(mut)]
+    pub fee_payer: Option<Signer<'info>>,
+    #[account(mut, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(init_if_needed, payer = fee_payer.as_ref().unwrap_or(&user), space = 8 + UserAccount::INIT_SPACE, seeds = [(b"user_account"), beneficiary.unwrap_or(user.key()).as_ref()], bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(seeds = [b"denylist", user.key().as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the wallet is denylisted
+    pub denylist_entry: UncheckedAccount<'info>,
+    #[account(seeds = [b"denylist", beneficiary.unwrap_or(user.key()).as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the beneficiary is denylisted
+    pub beneficiary_denylist_entry: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_usdt_ata: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub payment_wallet_usdt: Account<'info, TokenAccount>,
+    #[account(mut, associated_token::mint = sale_token, associated_token::authority = presale_account)]
+    pub presale_ata: Option<Account<'info, TokenAccount>>,
+    #[account(address = beneficiary.unwrap_or(user.key()))]
+    /// CHECK: Owner of the delivery ATA, only needed when buying for another wallet
+    pub beneficiary_wallet: Option<UncheckedAccount<'info>>,
+    #[account(init_if_needed, payer = fee_payer.as_ref().unwrap_or(&user), associated_token::mint = sale_token, associated_token::authority = beneficiary_wallet.as_ref().map_or(user.to_account_info(), |wallet| wallet.to_account_info()))]
+    pub user_sale_ata: Option<Account<'info, TokenAccount>>,
+    #[account(address = presale_account.sale_token)]
+    pub sale_token: Option<Account<'info, Mint>>,
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    #[account(mut)]
+    pub referrer_account: Option<Account<'info, ReferrerAccount>>,
+    #[account(mut)]
+    pub referrer_usdt_ata: Option<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub discount_code: Option<Account<'info, DiscountCode>>,
+    #[account(init_if_needed, payer = fee_payer.as_ref().unwrap_or(&user), space = 8 + DiscountRedemption::INIT_SPACE, seeds = [b"discount_redemption", discount_code.as_ref().map(|code| code.key()).unwrap_or_default().as_ref(), beneficiary.unwrap_or(user.key()).as_ref()], bump)]
+    pub discount_redemption: Option<Account<'info, DiscountRedemption>>,
+    #[account(address = sysvar::instructions::ID)]
+    /// CHECK: Instructions sysvar, only read to find the KYC attestation
+    pub instructions: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+#[instruction(user: Pubkey, amount: u128, usd_value: u128, external_ref: [u8; 32])]
+pub struct RecordExternalPurchase<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(mut, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(init_if_needed, payer = authority, space = 8 + UserAccount::INIT_SPACE, seeds = [b"user_account", user.as_ref()], bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(seeds = [b"denylist", user.as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the wallet is denylisted
+    pub denylist_entry: UncheckedAccount<'info>,
+    #[account(init, payer = authority, space = 8 + ExternalPurchase::INIT_SPACE, seeds = [b"external_purchase", external_ref.as_ref()], bump)]
+    pub external_purchase: Account<'info, ExternalPurchase>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ImportAllocations<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(wallet: Pubkey)]
+pub struct AdjustAllocation<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, seeds = [b"user_account", wallet.as_ref()], bump)]
+    pub user_account: Account<'info, UserAccount>,
+}
+
+#[derive(Accounts)]
+#[instruction(from: Pubkey, to: Pubkey)]
+pub struct MoveAllocation<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, seeds = [b"user_account", from.as_ref()], bump)]
+    pub from_user_account: Account<'info, UserAccount>,
+    #[account(init_if_needed, payer = owner, space = 8 + UserAccount::INIT_SPACE, seeds = [b"user_account", to.as_ref()], bump)]
+    pub to_user_account: Account<'info, UserAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(to: Pubkey)]
+pub struct TransferAllocation<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(mut, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, seeds = [b"user_account", user.key().as_ref()], bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(mut, address = user_account.rent_payer)]
+    /// CHECK: Refunded the user account rent, only needed when it was paid by another wallet
+    pub rent_payer: Option<UncheckedAccount<'info>>,
+    #[account(init_if_needed, payer = user, space = 8 + UserAccount::INIT_SPACE, seeds = [b"user_account", to.as_ref()], bump)]
+    pub to_user_account: Account<'info, UserAccount>,
+    #[account(seeds = [b"denylist", user.key().as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the wallet is denylisted
+    pub denylist_entry: UncheckedAccount<'info>,
+    #[account(seeds = [b"denylist", to.as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the recipient is denylisted
+    pub to_denylist_entry: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct StartClaim<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut)]
+    pub owner_ata: Account<'info, TokenAccount>,
+    #[account(init_if_needed, payer = owner, associated_token::mint = sale_token, associated_token::authority = presale_account)]
+    pub presale_ata: Account<'info, TokenAccount>,
+    pub sale_token: Account<'info, Mint>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositSaleTokens<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, token::mint = sale_token)]
+    pub owner_ata: Account<'info, TokenAccount>,
+    #[account(mut, associated_token::mint = sale_token, associated_token::authority = presale_account)]
+    pub presale_ata: Account<'info, TokenAccount>,
+    #[account(address = presale_account.sale_token)]
+    pub sale_token: Account<'info, Mint>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ChangeInstantDelivery<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(init_if_needed, payer = owner, associated_token::mint = sale_token, associated_token::authority = presale_account)]
+    pub presale_ata: Account<'info, TokenAccount>,
+    pub sale_token: Account<'info, Mint>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct StartClaimWithMint<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mint::authority = presale_account)]
+    pub sale_token: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeMintAuthority<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, address = presale_account.sale_token)]
+    pub sale_token: Account<'info, Mint>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Claim<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    /// Pays the rent for new accounts, falls back to `user` when not sponsored
+    #[account(mut)]
+    pub fee_payer: Option<Signer<'info>>,
+    #[account(mut, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, seeds = [b"user_account", user.key().as_ref()], bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(mut, address = user_account.rent_payer)]
+    /// CHECK: Refunded the user account rent, only needed when it was paid by another wallet
+    pub rent_payer: Option<UncheckedAccount<'info>>,
+    #[account(mut, associated_token::mint = sale_token, associated_token::authority = presale_account)]
+    pub presale_ata: Option<Account<'info, TokenAccount>>,
+    #[account(init_if_needed, payer = fee_payer.as_ref().unwrap_or(&user), associated_token::mint = sale_token, associated_token::authority = user)]
+    pub user_ata: Account<'info, TokenAccount>,
+    #[account(mut, address = presale_account.sale_token)]
+    pub sale_token: Account<'info, Mint>,
+    #[account(seeds = [b"claim_whitelist", user.key().as_ref()], bump)]
+    pub claim_whitelist_entry: Option<Account<'info, ClaimWhitelistEntry>>,
+    #[account(seeds = [b"denylist", user.key().as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the wallet is denylisted
+    pub denylist_entry: UncheckedAccount<'info>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(wallet: Pubkey)]
+pub struct AddClaimWhitelistUser<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(init, payer = owner, space = 8 + ClaimWhitelistEntry::INIT_SPACE, seeds = [b"claim_whitelist", wallet.as_ref()], bump)]
+    pub claim_whitelist_entry: Account<'info, ClaimWhitelistEntry>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(wallet: Pubkey)]
+pub struct RemoveClaimWhitelistUser<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, close = owner, seeds = [b"claim_whitelist", wallet.as_ref()], bump)]
+    pub claim_whitelist_entry: Account<'info, ClaimWhitelistEntry>,
+}
+
+#[derive(Accounts)]
+#[instruction(code_hash: [u8; 32])]
+pub struct CreateDiscountCode<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(init, payer = owner, space = 8 + DiscountCode::INIT_SPACE, seeds = [b"discount_code", code_hash.as_ref()], bump)]
+    pub discount_code: Account<'info, DiscountCode>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(code_hash: [u8; 32])]
+pub struct CloseDiscountCode<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, close = owner, seeds = [b"discount_code", code_hash.as_ref()], bump)]
+    pub discount_code: Account<'info, DiscountCode>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterReferrer<'info> {
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub referrer: Signer<'info>,
     #[account(mut, seeds = [b"presale_account"], bump)]
     pub presale_account: Account<'info, PresaleAccount>,
-    #[account(init_if_needed, payer = user, space = 8 + 16, seeds = [(b"user_account"), user.key().as_ref()], bump)]
-    pub user_account: Account<'info, UserAccount>,
-    #[account(mut)]
-    /// CHECK: To pass payment wallet as account info
-    pub payment_wallet: UncheckedAccount<'info>,
-    #[account(address = Pubkey::from_str(CHAINLINK_PROGRAM).unwrap())]
-    /// CHECK: This is safe as we are using chainlink program
-    pub chainlink_program: UncheckedAccount<'info>,
-    #[account(address = Pubkey::from_str(CHAINLINK_FEED).unwrap())]
-    /// CHECK: This is safe as we are using chainlink feed
-    pub chainlink_feed: UncheckedAccount<'info>,
+    #[account(init, payer = referrer, space = 8 + ReferrerAccount::INIT_SPACE, seeds = [b"referrer", referrer.key().as_ref()], bump)]
+    pub referrer_account: Account<'info, ReferrerAccount>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct BuyWithUSDT<'info> {
+pub struct ClaimReferralRewards<'info> {
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub referrer: Signer<'info>,
     #[account(mut, seeds = [b"presale_account"], bump)]
     pub presale_account: Account<'info, PresaleAccount>,
-    #[account(init_if_needed, payer = user, space = 8 + 16, seeds = [(b"user_account"), user.key().as_ref()], bump)]
-    pub user_account: Account<'info, UserAccount>,
-    #[account(mut)]
-    pub user_usdt_ata: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub payment_wallet_usdt: Account<'info, TokenAccount>,
+    #[account(mut, has_one = referrer, seeds = [b"referrer", referrer.key().as_ref()], bump)]
+    pub referrer_account: Account<'info, ReferrerAccount>,
+    #[account(mut, associated_token::mint = sale_token, associated_token::authority = presale_account)]
+    pub presale_ata: Option<Account<'info, TokenAccount>>,
+    #[account(init_if_needed, payer = referrer, associated_token::mint = sale_token, associated_token::authority = referrer)]
+    pub referrer_ata: Account<'info, TokenAccount>,
+    #[account(mut, address = presale_account.sale_token)]
+    pub sale_token: Account<'info, Mint>,
+    #[account(seeds = [b"denylist", referrer.key().as_ref()], bump)]
+    /// CHECK: Must stay uninitialized, an existing entry means the wallet is denylisted
+    pub denylist_entry: UncheckedAccount<'info>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
     pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct StartClaim<'info> {
+#[instruction(wallet: Pubkey)]
+pub struct AddDenylistUser<'info> {
     #[account(mut)]
-    pub owner: Signer<'info>,
-    #[account(mut, has_one = owner)]
+    pub authority: Signer<'info>,
+    #[account(seeds = [b"presale_account"], bump)]
     pub presale_account: Account<'info, PresaleAccount>,
+    #[account(init, payer = authority, space = 8 + DenylistEntry::INIT_SPACE, seeds = [b"denylist", wallet.as_ref()], bump)]
+    pub denylist_entry: Account<'info, DenylistEntry>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(wallet: Pubkey)]
+pub struct ReleaseDenylistUser<'info> {
     #[account(mut)]
-    pub owner_ata: Account<'info, TokenAccount>,
-    #[account(init_if_needed, payer = owner, associated_token::mint = sale_token, associated_token::authority = presale_account)]
+    pub owner: Signer<'info>,
+    #[account(has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, close = owner, seeds = [b"denylist", wallet.as_ref()], bump)]
+    pub denylist_entry: Account<'info, DenylistEntry>,
+}
+
+#[derive(Accounts)]
+#[instruction(wallet: Pubkey)]
+pub struct ReclaimDenylistedAllocation<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, seeds = [b"user_account", wallet.as_ref()], bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(seeds = [b"denylist", wallet.as_ref()], bump)]
+    pub denylist_entry: Account<'info, DenylistEntry>,
+}
+
+#[derive(Accounts)]
+pub struct BurnUnsoldTokens<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
+    #[account(mut, associated_token::mint = sale_token, associated_token::authority = presale_account)]
     pub presale_ata: Account<'info, TokenAccount>,
+    #[account(mut, address = presale_account.sale_token)]
     pub sale_token: Account<'info, Mint>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
     pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Claim<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(mut, seeds = [b"presale_account"], bump)]
+pub struct WithdrawSaleTokens<'info> {
+    pub owner: Signer<'info>,
+    #[account(mut, has_one = owner, seeds = [b"presale_account"], bump)]
     pub presale_account: Account<'info, PresaleAccount>,
-    #[account(mut, close = user, seeds = [b"user_account", user.key().as_ref()], bump)]
-    pub user_account: Account<'info, UserAccount>,
     #[account(mut, associated_token::mint = sale_token, associated_token::authority = presale_account)]
     pub presale_ata: Account<'info, TokenAccount>,
-    #[account(init_if_needed, payer = user, associated_token::mint = sale_token, associated_token::authority = user)]
-    pub user_ata: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = sale_token)]
+    pub owner_ata: Account<'info, TokenAccount>,
+    #[account(address = presale_account.sale_token)]
     pub sale_token: Account<'info, Mint>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
     pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Finalize<'info> {
+    #[account(mut, seeds = [b"presale_account"], bump)]
+    pub presale_account: Account<'info, PresaleAccount>,
 }
 
 #[derive(Accounts)]
@@ -812,8 +3176,365 @@ pub struct PresaleAccount {
     pub sale_token_decimals: u8,
     pub tokens_added: u128,
     pub tokens_claimed: u128,
-    #[max_len(5)]
-    pub whitelisted_users: Vec<Pubkey>,
+    pub status: PresaleStatus,
+    pub unsold_policy: UnsoldPolicy,
+    pub claim_deadline: u128,
+    pub mint_on_claim: bool,
+    pub instant_delivery: bool,
+    pub max_tokens_per_wallet: u128,
+    pub max_tokens_per_wallet_per_round: u128,
+    #[max_len(4)]
+    pub min_purchase_usd: Vec<u128>,
+    pub hard_cap_usd: u128,
+    #[max_len(4)]
+    pub round_merkle_roots: Vec<[u8; 32]>,
+    pub kyc_attester: Pubkey,
+    pub tokens_reclaimed: u128,
+    pub referral_reward_bps: u16,
+    pub referral_reward_mode: ReferralRewardMode,
+    pub referral_tokens_allocated: u128,
+    #[max_len(4)]
+    pub bonus_tiers: Vec<BonusTier>,
+    pub bonus_tokens_allocated: u128,
+    pub early_bird_bonus_bps: u16,
+    pub early_bird_duration: u128,
+    pub early_bird_tokens_allocated: u128,
+    pub private_allocated: u128,
+    pub allocation_transfers_disabled: bool,
+}
+
+impl PresaleAccount {
+    /// Applies the time and round driven transitions: `Pending` becomes `Active` once
+    /// `start_time` has passed and `Active` becomes `Ended` once every round is closed.
+    pub fn sync_status(&mut self, current_time: u128) {
+        if self.status == PresaleStatus::Pending && current_time > self.start_time {
+            self.status = PresaleStatus::Active;
+        }
+
+        if self.status == PresaleStatus::Active
+            && self.current_round >= self.rounds[0].len() as u128
+        {
+            self.status = PresaleStatus::Ended;
+        }
+    }
+
+    /// Pushes the unsold amount of every round from `current_round` up to, but excluding,
+    /// `new_round`. `sale_amount` is the cumulative amount sold against the round caps.
+    pub fn record_unsold_tokens(&mut self, new_round: u128, sale_amount: u128) {
+        for round in self.current_round..new_round {
+            let value = if round == self.current_round {
+                self.rounds[0][round as usize].saturating_sub(sale_amount)
+            } else {
+                self.rounds[0][round as usize] - self.rounds[0][(round - 1) as usize]
+            };
+            self.unsold_tokens.push(value);
+        }
+    }
+
+    /// Drains the recorded unsold tokens so they can leave the presale ATA, keeping
+    /// `tokens_added` in line with what remains deposited for buyers.
+    pub fn take_unsold_tokens(&mut self) -> Result<u128> {
+        let amount: u128 = self.unsold_tokens.iter().sum();
+        require!(amount > 0, PresaleErrors::NoUnsoldTokens);
+        require!(
+            self.tokens_added >= self.tokens_owed() + amount,
+            PresaleErrors::InsufficientSurplusTokens
+        );
+
+        self.tokens_added -= amount;
+        self.unsold_tokens.iter_mut().for_each(|unsold| *unsold = 0);
+
+        Ok(amount)
+    }
+
+    /// True once the USD left under the hard cap cannot pay for a single token of the
+    /// current round.
+    pub fn hard_cap_reached(&self) -> bool {
+        self.hard_cap_usd != 0
+            && self.current_round < self.rounds[0].len() as u128
+            && self.hard_cap_usd.saturating_sub(self.usd_raised)
+                < self.rounds[1][self.current_round as usize]
+    }
+
+    /// Closes every remaining round, recording what was left unsold, and ends the sale.
+    pub fn end_sale(&mut self, current_time: u128) {
+        let last_round = self.rounds[0].len() - 1;
+        let sale_amount = if self.current_tracker == 0 {
+            self.tokens_sold
+        } else {
+            self.current_tracker
+        };
+
+        let end_round = self.rounds[0].len() as u128;
+        self.record_unsold_tokens(end_round, sale_amount);
+        self.current_tracker = self.rounds[0][last_round];
+        self.current_round = end_round;
+        self.sync_status(current_time);
+
+        msg!("Presale ended with {} tokens sold", self.tokens_sold);
+
+        emit!(PresaleEnded {
+            tokens_sold: self.tokens_sold,
+            unsold_tokens: self.unsold_tokens.clone(),
+            timestamp: current_time,
+        });
+    }
+
+    /// Allowlist root gating `round`, all zeroes when the round is open to everyone.
+    pub fn merkle_root_for(&self, round: u128) -> [u8; 32] {
+        self.round_merkle_roots
+            .get(round as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn min_purchase_usd_for(&self, round: u128) -> u128 {
+        self.min_purchase_usd
+            .get(round as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Tokens a wallet may still buy under the lifetime and per-round caps, where a zero
+    /// cap means unlimited.
+    pub fn wallet_allowance(&self, purchased: u128, round_purchased: u128) -> u128 {
+        let wallet_remaining = if self.max_tokens_per_wallet == 0 {
+            u128::MAX
+        } else {
+            self.max_tokens_per_wallet.saturating_sub(purchased)
+        };
+        let round_remaining = if self.max_tokens_per_wallet_per_round == 0 {
+            u128::MAX
+        } else {
+            self.max_tokens_per_wallet_per_round
+                .saturating_sub(round_purchased)
+        };
+
+        wallet_remaining.min(round_remaining)
+    }
+
+    /// Part of a purchase that fills up the current round before it sells out into
+    /// `new_round`, zero when the round stays or moves on by time.
+    pub fn sellout_fill(&self, new_round: u128, amount: u128, current_time: u128) -> u128 {
+        if new_round == self.current_round
+            || current_time >= self.rounds[2][self.current_round as usize]
+        {
+            return 0;
+        }
+
+        let sale_amount = if self.current_tracker == 0 {
+            self.tokens_sold
+        } else {
+            self.current_tracker
+        };
+        self.rounds[0][self.current_round as usize]
+            .saturating_sub(sale_amount)
+            .min(amount)
+    }
+
+    /// Upper bound on what may ever be minted to claimers in mint-on-claim mode.
+    pub fn mint_cap(&self) -> u128 {
+        self.tokens_owed()
+    }
+
+    /// Sale tokens buyers, private investors and referrers are entitled to, bonuses
+    /// included, net of allocations reclaimed from denylisted wallets.
+    pub fn tokens_owed(&self) -> u128 {
+        self.tokens_sold
+            + self.bonus_tokens_allocated
+            + self.early_bird_tokens_allocated
+            + self.referral_tokens_allocated
+            + self.private_allocated
+            - self.tokens_reclaimed
+    }
+
+    /// Early bird bonus decaying linearly from `early_bird_bonus_bps` at the round start
+    /// to zero after `early_bird_duration`, or at the round end if that comes first.
+    pub fn early_bird_bonus_bps_at(&self, round: u128, current_time: u128) -> u16 {
+        if self.early_bird_bonus_bps == 0 || self.early_bird_duration == 0 {
+            return 0;
+        }
+
+        let round_start = if round == 0 {
+            self.start_time
+        } else {
+            self.rounds[2][(round - 1) as usize]
+        };
+        let bonus_end =
+            (round_start + self.early_bird_duration).min(self.rounds[2][round as usize]);
+        if current_time >= bonus_end || bonus_end <= round_start {
+            return 0;
+        }
+
+        let elapsed = current_time.saturating_sub(round_start);
+        let window = bonus_end - round_start;
+        (self.early_bird_bonus_bps as u128 * (window - elapsed) / window) as u16
+    }
+
+    /// Bonus of the highest tier whose `min_usd` the purchase reaches.
+    pub fn bonus_bps_for(&self, price_in_usd: u128) -> u16 {
+        self.bonus_tiers
+            .iter()
+            .rev()
+            .find(|tier| price_in_usd >= tier.min_usd)
+            .map_or(0, |tier| tier.bonus_bps)
+    }
+
+    /// Sale tokens currently sitting in the presale ATA.
+    pub fn available_sale_tokens(&self) -> u128 {
+        self.tokens_added.saturating_sub(self.tokens_claimed)
+    }
+
+    pub fn claim_window_closed(&self, current_time: u128) -> bool {
+        self.claim_deadline != 0 && current_time > self.claim_deadline
+    }
+
+    /// Unsold tokens set aside for a burn or rollover, which the surplus must not touch.
+    pub fn reserved_unsold_tokens(&self) -> u128 {
+        if self.unsold_policy == UnsoldPolicy::ReturnToOwner {
+            0
+        } else {
+            self.unsold_tokens.iter().sum()
+        }
+    }
+
+    /// Sale tokens the owner may take back: anything deposited beyond what was sold and,
+    /// once the claim window has closed, whatever buyers left unclaimed.
+    pub fn withdrawable_surplus(&self, current_time: u128) -> u128 {
+        let reserved = self.reserved_unsold_tokens();
+        if self.claim_window_closed(current_time) {
+            self.available_sale_tokens().saturating_sub(reserved)
+        } else {
+            self.tokens_added
+                .saturating_sub(self.tokens_owed() + reserved)
+        }
+    }
+
+    pub fn require_status(&self, allowed: &[PresaleStatus]) -> Result<()> {
+        require!(
+            allowed.contains(&self.status),
+            PresaleErrors::InvalidPresaleStatus
+        );
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum UnsoldPolicy {
+    ReturnToOwner,
+    Rollover,
+    Burn,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum ReferralRewardMode {
+    SaleToken,
+    PaymentCurrency,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug, InitSpace)]
+pub enum PresaleStatus {
+    Pending,
+    Active,
+    Ended,
+    Finalized,
+    Claiming,
+    Cancelled,
+    Closed,
+}
+
+/// Purchase requested by `payer` and credited to `beneficiary`'s user account.
+pub struct PurchaseOrder {
+    pub payer: Pubkey,
+    pub beneficiary: Pubkey,
+    pub amount: u128,
+    pub allow_partial: bool,
+    pub allowlist_proof: Option<AllowlistProof>,
+    pub discount: Option<PurchaseDiscount>,
+}
+
+/// Discount code redeemed for a purchase, taken off its USD price.
+pub struct PurchaseDiscount {
+    pub code_hash: [u8; 32],
+    pub discount_bps: u16,
+}
+
+/// Proof that `keccak(beneficiary, allocation)` is a leaf of the round's allowlist root. A zero
+/// `allocation` leaves the wallet uncapped within the round.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AllowlistProof {
+    pub allocation: u128,
+    pub proof: Vec<[u8; 32]>,
+}
+
+/// Private sale allocation imported for `wallet`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct AllocationEntry {
+    pub wallet: Pubkey,
+    pub amount: u128,
+    pub vesting_class: u8,
+}
+
+/// Bonus tokens, in basis points of the purchased amount, for purchases costing at least
+/// `min_usd` (same units as `price_in_usd`).
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, InitSpace)]
+pub struct BonusTier {
+    pub min_usd: u128,
+    pub bonus_bps: u16,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct ClaimWhitelistEntry {
+    pub wallet: Pubkey,
+    pub added_at: u128,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct ReferrerAccount {
+    pub referrer: Pubkey,
+    pub registered_at: u128,
+    pub referred_count: u128,
+    pub referred_usd: u128,
+    pub reward_usd: u128,
+    pub reward_tokens: u128,
+    pub claimed_reward_tokens: u128,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct ExternalPurchase {
+    pub user: Pubkey,
+    pub amount: u128,
+    pub usd_value: u128,
+    pub external_ref: [u8; 32],
+    pub recorded_at: u128,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct DiscountCode {
+    pub code_hash: [u8; 32],
+    pub discount_bps: u16,
+    pub max_redemptions: u128,
+    pub max_redemptions_per_wallet: u128,
+    pub redemptions: u128,
+    pub expiry: u128,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct DiscountRedemption {
+    pub redemptions: u128,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct DenylistEntry {
+    pub wallet: Pubkey,
+    pub reason_code: u16,
+    pub added_at: u128,
 }
 
 #[account]
@@ -832,8 +3553,251 @@ impl CalculateReturn {
 }
 
 #[account]
+#[derive(InitSpace, Default)]
 pub struct UserAccount {
     pub purchased_amount: u128,
+    pub claimed_amount: u128,
+    pub round_purchased: [u128; MAX_ROUNDS],
+    pub kyc_tier: u8,
+    pub reclaimed_amount: u128,
+    pub bonus_amount: u128,
+    pub early_bird_bonus_amount: u128,
+    pub private_amount: u128,
+    pub vesting_class: u8,
+    pub rent_payer: Pubkey,
+}
+
+impl UserAccount {
+    /// Remembers the wallet that funded a new account so closing it refunds that wallet.
+    pub fn record_rent_payer(&mut self, payer: Pubkey) {
+        if self.rent_payer == Pubkey::default() {
+            self.rent_payer = payer;
+        }
+    }
+
+    pub fn round_purchased_amount(&self, round: u128) -> u128 {
+        self.round_purchased
+            .get(round as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Everything allocated to the wallet: purchases, bonuses and private allocations.
+    pub fn entitled_amount(&self) -> u128 {
+        self.purchased_amount
+            + self.bonus_amount
+            + self.early_bird_bonus_amount
+            + self.private_amount
+    }
+
+    pub fn claimable_amount(&self) -> u128 {
+        self.entitled_amount()
+            .saturating_sub(self.claimed_amount + self.reclaimed_amount)
+    }
+
+    /// Moves every allocation counter of `other` into this account, leaving `other` empty.
+    /// An empty account takes over the vesting class and KYC tier of `other`, otherwise the
+    /// vesting classes must match and the lower KYC tier is kept.
+    pub fn take_allocation_from(&mut self, other: &mut UserAccount) -> Result<()> {
+        if self.entitled_amount() == 0 && self.claimed_amount == 0 && self.reclaimed_amount == 0 {
+            self.vesting_class = other.vesting_class;
+            self.kyc_tier = other.kyc_tier;
+        }
+        require!(
+            self.vesting_class == other.vesting_class,
+            PresaleErrors::VestingClassMismatch
+        );
+        self.kyc_tier = self.kyc_tier.min(other.kyc_tier);
+
+        self.purchased_amount += std::mem::take(&mut other.purchased_amount);
+        self.claimed_amount += std::mem::take(&mut other.claimed_amount);
+        self.reclaimed_amount += std::mem::take(&mut other.reclaimed_amount);
+        self.bonus_amount += std::mem::take(&mut other.bonus_amount);
+        self.early_bird_bonus_amount += std::mem::take(&mut other.early_bird_bonus_amount);
+        self.private_amount += std::mem::take(&mut other.private_amount);
+        for (round_purchased, other_round_purchased) in self
+            .round_purchased
+            .iter_mut()
+            .zip(other.round_purchased.iter_mut())
+        {
+            *round_purchased += std::mem::take(other_round_purchased);
+        }
+
+        Ok(())
+    }
+}
+
+#[event]
+pub struct ClaimWhitelistUpdated {
+    pub wallet: Pubkey,
+    pub whitelisted: bool,
+}
+
+#[event]
+pub struct DenylistUpdated {
+    pub wallet: Pubkey,
+    pub reason_code: u16,
+    pub denylisted: bool,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct DenylistedAllocationReclaimed {
+    pub wallet: Pubkey,
+    pub amount: u128,
+    pub reason_code: u16,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct ReferralRewarded {
+    pub referrer: Pubkey,
+    pub buyer: Pubkey,
+    pub mode: ReferralRewardMode,
+    pub reward_usd: u128,
+    pub reward_tokens: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct DiscountCodeRedeemed {
+    pub code_hash: [u8; 32],
+    pub buyer: Pubkey,
+    pub discount_usd: u128,
+    pub price_in_usd: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct ExternalPurchaseRecorded {
+    pub user: Pubkey,
+    pub amount: u128,
+    pub usd_value: u128,
+    pub external_ref: [u8; 32],
+    pub round: u128,
+    pub recorded_by: Pubkey,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct AllocationImported {
+    pub wallet: Pubkey,
+    pub amount: u128,
+    pub vesting_class: u8,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct AllocationAdjusted {
+    pub wallet: Pubkey,
+    pub reason_code: u16,
+    pub previous_purchased_amount: u128,
+    pub new_purchased_amount: u128,
+    pub previous_tokens_sold: u128,
+    pub new_tokens_sold: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct AllocationMoved {
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub reason_code: u16,
+    pub previous_from_amount: u128,
+    pub previous_to_amount: u128,
+    pub new_to_amount: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct AllocationTransferred {
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub amount: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct TokensPurchased {
+    pub payer: Pubkey,
+    pub beneficiary: Pubkey,
+    pub amount: u128,
+    pub requested_amount: u128,
+    pub bonus_amount: u128,
+    pub early_bird_bonus_amount: u128,
+    pub price_in_usd: u128,
+    pub round: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct PresaleEnded {
+    pub tokens_sold: u128,
+    pub unsold_tokens: Vec<u128>,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct UnsoldTokensRolledOver {
+    pub round: u128,
+    pub into_round: u128,
+    pub amount: u128,
+    pub current_tracker: u128,
+}
+
+#[event]
+pub struct UnsoldTokensBurned {
+    pub amount: u128,
+    pub tokens_added: u128,
+}
+
+#[event]
+pub struct UnsoldTokensWithdrawn {
+    pub amount: u128,
+    pub to: Pubkey,
+    pub tokens_added: u128,
+}
+
+#[event]
+pub struct SaleTokensDeposited {
+    pub amount: u128,
+    pub tokens_added: u128,
+}
+
+#[event]
+pub struct MintAuthorityRevoked {
+    pub sale_token: Pubkey,
+    pub tokens_minted: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct SurplusTokensWithdrawn {
+    pub amount: u128,
+    pub to: Pubkey,
+    pub includes_unclaimed: bool,
+    pub tokens_added: u128,
+}
+
+#[event]
+pub struct PresaleFinalized {
+    pub tokens_sold: u128,
+    pub usd_raised: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct PresaleCancelled {
+    pub previous_status: PresaleStatus,
+    pub tokens_sold: u128,
+    pub usd_raised: u128,
+    pub timestamp: u128,
+}
+
+#[event]
+pub struct PresaleClosed {
+    pub previous_status: PresaleStatus,
+    pub timestamp: u128,
 }
 
 #[error_code]
@@ -858,7 +3822,7 @@ pub enum PresaleErrors {
     IncorrectPaymentWallet,
     #[msg("Presale not ended")]
     PresaleNotEnded,
-    #[msg("Tokens to add should be greater than tokens sold")]
+    #[msg("Tokens added should cover every token owed to buyers")]
     IncorrectSaleTokenAdded,
     #[msg("Incorrect claim start time, claim should be in future")]
     IncorrectClaimStartTime,
@@ -870,8 +3834,80 @@ pub enum PresaleErrors {
     NothingToClaim,
     #[msg("Only owner or admin")]
     AccessRestricted,
-    #[msg("Invalid length for whitelisted users")]
+    #[msg("Rounds need three rows of one to four entries each")]
     InvalidLength,
     #[msg("User is not whitelisted for claim")]
     NotClaimWhitelisted,
+    #[msg("Instruction not allowed in current presale status")]
+    InvalidPresaleStatus,
+    #[msg("Sold tokens are still waiting to be claimed")]
+    UnclaimedTokensRemaining,
+    #[msg("Action not allowed by the unsold tokens policy")]
+    InvalidUnsoldPolicy,
+    #[msg("No unsold tokens to process")]
+    NoUnsoldTokens,
+    #[msg("Not enough surplus sale tokens deposited")]
+    InsufficientSurplusTokens,
+    #[msg("Claim window has closed")]
+    ClaimWindowClosed,
+    #[msg("Claim deadline should be in future and after claim start")]
+    InvalidClaimDeadline,
+    #[msg("Not enough sale tokens deposited to cover this claim")]
+    InsufficientSaleTokenLiquidity,
+    #[msg("Required account was not provided")]
+    MissingOptionalAccount,
+    #[msg("Claim would exceed the mint cap")]
+    MintCapExceeded,
+    #[msg("Not allowed while mint on claim is enabled")]
+    MintOnClaimEnabled,
+    #[msg("Mint on claim is not enabled")]
+    MintOnClaimDisabled,
+    #[msg("Sale token does not match the deposited sale token")]
+    SaleTokenMismatch,
+    #[msg("Amount exceeds the wallet purchase cap")]
+    WalletCapExceeded,
+    #[msg("Per round values do not match the rounds")]
+    InvalidRoundConfig,
+    #[msg("Purchase is below the minimum amount in USD")]
+    BelowMinPurchase,
+    #[msg("Hard cap in USD reached")]
+    HardCapReached,
+    #[msg("Wallet is not on the round allowlist")]
+    NotAllowlisted,
+    #[msg("Amount exceeds the allowlist allocation")]
+    AllowlistAllocationExceeded,
+    #[msg("KYC attestation instruction is missing")]
+    MissingKycAttestation,
+    #[msg("KYC attestation does not match the attester, buyer or presale")]
+    InvalidKycAttestation,
+    #[msg("KYC attestation has expired")]
+    KycAttestationExpired,
+    #[msg("Wallet is denylisted")]
+    WalletDenylisted,
+    #[msg("Referral reward should be at most 10000 bps")]
+    InvalidReferralReward,
+    #[msg("Referrer does not match the referrer account or is the buyer")]
+    InvalidReferrer,
+    #[msg("Bonus tiers should be at most 4, sorted by min USD and at most 10000 bps")]
+    InvalidBonusTiers,
+    #[msg("Early bird bonus should be at most 10000 bps")]
+    InvalidEarlyBirdBonus,
+    #[msg("Discount should be at most 10000 bps with an expiry in future")]
+    InvalidDiscountCode,
+    #[msg("Discount code has expired")]
+    DiscountCodeExpired,
+    #[msg("Discount code has no redemptions left")]
+    DiscountCodeExhausted,
+    #[msg("Wallet reached the discount code redemption limit")]
+    DiscountCodeWalletLimit,
+    #[msg("User accounts do not match the allocation entries")]
+    AllocationAccountsMismatch,
+    #[msg("Allocation change would drop below claimed tokens or targets the same wallet")]
+    InvalidAllocationAdjustment,
+    #[msg("Allocation transfers are disabled")]
+    AllocationTransfersDisabled,
+    #[msg("Allocation vesting class does not match the user account")]
+    VestingClassMismatch,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
 }
@@ -2,6 +2,7 @@ import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { Presale } from "../target/types/presale";
 import { assert } from "chai";
+import { keccak_256 } from "@noble/hashes/sha3";
 import * as spl from "@solana/spl-token";
 import {
   createMint,
@@ -30,11 +31,29 @@ const test = async () => {
     program.programId
   );
 
+  const [userClaimWhitelistPDA] = anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("claim_whitelist"), user.publicKey.toBytes()],
+    program.programId
+  );
+
+  const [userDenylistPDA] = anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("denylist"), user.publicKey.toBytes()],
+    program.programId
+  );
+
   const user2 = anchor.web3.Keypair.generate();
   const [userPDA2] = anchor.web3.PublicKey.findProgramAddressSync(
     [Buffer.from("user_account"), user2.publicKey.toBytes()],
     program.programId
   );
+  const [user2DenylistPDA] = anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("denylist"), user2.publicKey.toBytes()],
+    program.programId
+  );
+  const [user2ReferrerPDA] = anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("referrer"), user2.publicKey.toBytes()],
+    program.programId
+  );
 
   const tokenProgram = TOKEN_PROGRAM_ID;
 
@@ -59,6 +78,8 @@ const test = async () => {
 
   const { SystemProgram } = anchor.web3;
   let currentTime = 0;
+  // Far above what the test buys raise, crossings are only simulated
+  const hardCapUsd = new anchor.BN("10000000000000");
   let rounds = [
     [
       new anchor.BN(100),
@@ -101,6 +122,46 @@ const test = async () => {
     return tokenAccount;
   };
 
+  // Runs the instructions without committing them, so scenarios that would end or
+  // reconfigure the singleton presale can be checked mid-lifecycle.
+  const simulate = async (
+    instructions: anchor.web3.TransactionInstruction[],
+    addresses: anchor.web3.PublicKey[] = []
+  ) => {
+    const { blockhash } = await provider.connection.getLatestBlockhash();
+    const message = new anchor.web3.TransactionMessage({
+      payerKey: provider.wallet.publicKey,
+      recentBlockhash: blockhash,
+      instructions,
+    }).compileToV0Message();
+
+    const { value } = await provider.connection.simulateTransaction(
+      new anchor.web3.VersionedTransaction(message),
+      {
+        sigVerify: false,
+        accounts: {
+          encoding: "base64",
+          addresses: addresses.map((address) => address.toBase58()),
+        },
+      }
+    );
+    const eventParser = new anchor.EventParser(
+      program.programId,
+      program.coder
+    );
+
+    return {
+      errorCode: value.err
+        ? value.err["InstructionError"]?.[1]?.["Custom"]
+        : null,
+      failed: value.err != null,
+      events: [...eventParser.parseLogs(value.logs ?? [])],
+      accountData: (value.accounts ?? []).map((account) =>
+        Buffer.from(account.data[0], "base64")
+      ),
+    };
+  };
+
   before(async () => {
     let slot = await provider.connection.getSlot();
     currentTime = await provider.connection.getBlockTime(slot);
@@ -312,6 +373,7 @@ const test = async () => {
         presaleAccount.currentRound.toString() == "0",
         "Current step init failed"
       );
+      assert("pending" in presaleAccount.status, "Status init failed");
     });
   });
 
@@ -650,6 +712,29 @@ const test = async () => {
       );
     });
 
+    it("Should not allow owner to change rounds with uneven rows", async () => {
+      let isError = false;
+
+      try {
+        await program.methods
+          .changeRounds([rounds[0], rounds[1], rounds[2].slice(0, 3)])
+          .accounts({
+            presaleAccount: presalePDA,
+            owner: tempOwner.publicKey,
+          })
+          .signers([tempOwner])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6016,
+          "Rounds length check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
     it("Should allow owner to pause presale", async () => {
       await program.methods
         .pausePresale()
@@ -891,15 +976,107 @@ const test = async () => {
       );
     });
 
+    it("Should allow the owner to set the hard cap before start", async () => {
+      await program.methods
+        .changeHardCapUsd(hardCapUsd)
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(
+        presaleAccountData.hardCapUsd.eq(hardCapUsd),
+        "Hard cap update failed"
+      );
+    });
+
+    it("Should allow the owner to enable instant delivery with a deposit", async () => {
+      await program.methods
+        .changeInstantDelivery(true)
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          presaleAta: presaleSaleTokenATA,
+          saleToken: saleToken,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+      await program.methods
+        .depositSaleTokens(new anchor.BN(25))
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          ownerAta: ownerSaleTokenATA,
+          presaleAta: presaleSaleTokenATA,
+          saleToken: saleToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(presaleAccountData.instantDelivery, "Instant delivery failed");
+      assert(
+        presaleAccountData.tokensAdded.toString() == "25",
+        "Tokens added update failed"
+      );
+    });
+
+    it("Should let the owner cancel and close the presale before start", async () => {
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .cancelPresale()
+            .accounts({
+              presaleAccount: presalePDA,
+              owner: provider.wallet.publicKey,
+            })
+            .instruction(),
+          await program.methods
+            .closePresale()
+            .accounts({
+              presaleAccount: presalePDA,
+              owner: provider.wallet.publicKey,
+            })
+            .instruction(),
+        ],
+        [presalePDA]
+      );
+      assert(!failed, "Cancel and close failed");
+
+      const cancelled = events.find(
+        (event) => event.name == "PresaleCancelled"
+      );
+      assert("pending" in cancelled.data.previousStatus, "Cancel event failed");
+      const closed = events.find((event) => event.name == "PresaleClosed");
+      assert("cancelled" in closed.data.previousStatus, "Close event failed");
+
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert("closed" in presaleAfter.status, "Status close failed");
+    });
+
     it("Should not let users buy before start time", async () => {
       let isError = false;
       try {
         await program.methods
-          .buyWithSol(new anchor.BN(1))
+          .buyWithSol(new anchor.BN(1), false, null, null)
           .accounts({
             presaleAccount: presalePDA,
             user: user.publicKey,
             userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
             paymentWallet: paymentWallet.publicKey,
             systemProgram: SystemProgram.programId,
             chainlinkFeed: chainlinkFeed,
@@ -921,13 +1098,19 @@ const test = async () => {
     it("Should let users buy after start", async () => {
       await new Promise((resolve) => setTimeout(resolve, 30000));
       await program.methods
-        .buyWithUsdt(new anchor.BN(25))
+        .buyWithUsdt(new anchor.BN(25), false, null, null)
         .accounts({
           user: user.publicKey,
           userAccount: userPDA,
+          denylistEntry: userDenylistPDA,
+          beneficiaryDenylistEntry: userDenylistPDA,
           userUsdtAta: userATA,
           presaleAccount: presalePDA,
           paymentWalletUsdt: paymentWalletUSDT,
+          presaleAta: presaleSaleTokenATA,
+          userSaleAta: userSaleTokenATA,
+          saleToken: saleToken,
+          associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
           systemProgram: SystemProgram.programId,
           tokenProgram: tokenProgram,
         })
@@ -940,87 +1123,200 @@ const test = async () => {
         presaleAccountData.tokensSold.toString() == "25",
         "Tokens sold update failed"
       );
+      assert(
+        presaleAccountData.tokensClaimed.toString() == "25",
+        "Tokens claimed update failed"
+      );
       const userAccountData = await program.account.userAccount.fetch(userPDA);
       assert(
         userAccountData.purchasedAmount.toString() == "25",
         "User purchased amount update failed"
       );
+      assert(
+        userAccountData.claimedAmount.toString() == "25",
+        "User claimed amount update failed"
+      );
+
+      const info = await provider.connection.getTokenAccountBalance(
+        userSaleTokenATA
+      );
+      assert(info.value.amount == "25000000", "Instant delivery failed");
     });
 
-    it("Should not let users buy zero amount", async () => {
+    it("Should allow the owner to turn off instant delivery after start", async () => {
+      await program.methods
+        .changeInstantDelivery(false)
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          presaleAta: presaleSaleTokenATA,
+          saleToken: saleToken,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(!presaleAccountData.instantDelivery, "Instant delivery failed");
+    });
+
+    it("Should not allow the owner to cancel the presale once it started", async () => {
       let isError = false;
       try {
         await program.methods
-          .buyWithSol(new anchor.BN(0))
+          .cancelPresale()
           .accounts({
             presaleAccount: presalePDA,
+            owner: provider.wallet.publicKey,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6018, "Status check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should report remaining wallet allowance", async () => {
+      await program.methods
+        .changeWalletCaps(new anchor.BN(300), new anchor.BN(0))
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const allowance = await program.methods
+        .remainingAllowance()
+        .accounts({
+          user: user.publicKey,
+          presaleAccount: presalePDA,
+          userAccount: userPDA,
+        })
+        .view();
+      assert(allowance.toString() == "275", "Wallet allowance failed");
+    });
+
+    it("Should not let users buy more than the wallet cap", async () => {
+      await program.methods
+        .changeWalletCaps(new anchor.BN(30), new anchor.BN(0))
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      // 25 tokens are already bought, so 6 more cross the 30 token cap
+      let isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(6), false, null, null)
+          .accounts({
             user: user.publicKey,
             userAccount: userPDA,
-            paymentWallet: paymentWallet.publicKey,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            userUsdtAta: userATA,
+            presaleAccount: presalePDA,
+            paymentWalletUsdt: paymentWalletUSDT,
             systemProgram: SystemProgram.programId,
-            chainlinkFeed: chainlinkFeed,
-            chainlinkProgram: chainlinkProgram,
+            tokenProgram: tokenProgram,
           })
           .signers([user])
           .rpc();
       } catch (error) {
         let err = error as anchor.AnchorError;
-        assert(err.error.errorCode.number == 6004, "Zero amount check failed");
+        assert(err.error.errorCode.number == 6031, "Wallet cap check failed");
         isError = true;
       }
       assert(isError, "Test case failed");
+
+      await program.methods
+        .changeWalletCaps(new anchor.BN(300), new anchor.BN(0))
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
     });
 
-    it("Should not let users to buy if presale is paused", async () => {
-      let isError = false;
+    it("Should not let users buy more than the per round wallet cap", async () => {
       await program.methods
-        .pausePresale()
+        .changeWalletCaps(new anchor.BN(0), new anchor.BN(20))
         .accounts({
           presaleAccount: presalePDA,
           owner: provider.wallet.publicKey,
         })
         .rpc();
+
+      let isError = false;
       try {
         await program.methods
-          .buyWithSol(new anchor.BN(10))
+          .buyWithUsdt(new anchor.BN(1), false, null, null)
           .accounts({
-            presaleAccount: presalePDA,
             user: user.publicKey,
             userAccount: userPDA,
-            paymentWallet: paymentWallet.publicKey,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            userUsdtAta: userATA,
+            presaleAccount: presalePDA,
+            paymentWalletUsdt: paymentWalletUSDT,
             systemProgram: SystemProgram.programId,
-            chainlinkFeed: chainlinkFeed,
-            chainlinkProgram: chainlinkProgram,
+            tokenProgram: tokenProgram,
           })
           .signers([user])
           .rpc();
       } catch (error) {
         let err = error as anchor.AnchorError;
         assert(
-          err.error.errorCode.number == 6007,
-          "Presale pause check failed"
+          err.error.errorCode.number == 6031,
+          "Round wallet cap check failed"
         );
         isError = true;
       }
       assert(isError, "Test case failed");
-    });
 
-    it("Should not let users to buy more than max tokens in single transaction", async () => {
-      let isError = false;
       await program.methods
-        .unpausePresale()
+        .changeWalletCaps(new anchor.BN(300), new anchor.BN(0))
         .accounts({
           presaleAccount: presalePDA,
           owner: provider.wallet.publicKey,
         })
         .rpc();
+    });
+
+    it("Should not allow to finalize before presale ends", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .finalize()
+          .accounts({
+            presaleAccount: presalePDA,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6009, "Presale end check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let users buy zero amount", async () => {
+      let isError = false;
       try {
         await program.methods
-          .buyWithSol(new anchor.BN(100))
+          .buyWithSol(new anchor.BN(0), false, null, null)
           .accounts({
             presaleAccount: presalePDA,
             user: user.publicKey,
             userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
             paymentWallet: paymentWallet.publicKey,
             systemProgram: SystemProgram.programId,
             chainlinkFeed: chainlinkFeed,
@@ -1030,83 +1326,1030 @@ const test = async () => {
           .rpc();
       } catch (error) {
         let err = error as anchor.AnchorError;
-        assert(err.error.errorCode.number == 6002, "Max tokens check failed");
+        assert(err.error.errorCode.number == 6004, "Zero amount check failed");
         isError = true;
       }
       assert(isError, "Test case failed");
     });
 
-    it("Should not let owner change start time once presale starts", async () => {
+    it("Should not let users buy below the minimum purchase", async () => {
+      await program.methods
+        .changeMinPurchaseUsd([new anchor.BN(2_000_000_000)])
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
       let isError = false;
       try {
         await program.methods
-          .changeStartTime(new anchor.BN(100000000000000))
+          .buyWithUsdt(new anchor.BN(1), false, null, null)
           .accounts({
+            user: user.publicKey,
+            userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            userUsdtAta: userATA,
             presaleAccount: presalePDA,
-            owner: provider.wallet.publicKey,
+            paymentWalletUsdt: paymentWalletUSDT,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: tokenProgram,
           })
+          .signers([user])
           .rpc();
       } catch (error) {
         let err = error as anchor.AnchorError;
-        assert(
-          err.error.errorCode.number == 6001,
-          "Presale already started check failed"
-        );
+        assert(err.error.errorCode.number == 6033, "Min purchase check failed");
         isError = true;
       }
       assert(isError, "Test case failed");
-    });
 
-    it("Should let user to buy upto max tokens to buy", async () => {
       await program.methods
-        .buyWithUsdt(new anchor.BN(50))
+        .changeMinPurchaseUsd([])
         .accounts({
-          user: user2.publicKey,
-          userAccount: userPDA2,
-          userUsdtAta: user2ATA,
           presaleAccount: presalePDA,
-          paymentWalletUsdt: paymentWalletUSDT,
-          systemProgram: SystemProgram.programId,
-          tokenProgram: tokenProgram,
+          owner: provider.wallet.publicKey,
         })
-        .signers([user2])
         .rpc();
-      const presaleAccountData = await program.account.presaleAccount.fetch(
-        presalePDA
-      );
-      assert(
-        presaleAccountData.tokensSold.toString() == "75",
-        "Tokens sold update failed"
-      );
-      const userAccountData = await program.account.userAccount.fetch(userPDA2);
-      assert(
-        userAccountData.purchasedAmount.toString() == "50",
-        "User purchased amount update failed"
-      );
     });
 
-    it("Should calculate tokens price accordingly on sellout", async () => {
-      const resp = await program.methods
-        .calculatePrice(new anchor.BN(50))
+    it("Should not let users outside the allowlist buy in a gated round", async () => {
+      await program.methods
+        .changeRoundMerkleRoots([Array(32).fill(1)])
         .accounts({
           presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
         })
-        .view();
-      assert(
-        resp.priceInUsd.toString() == "75000000000",
-        "Price on sellout check failed"
-      );
-      assert(resp.newRound.toString() == "1", "New round suggest failed");
-    });
+        .rpc();
 
-    it("Should calculate tokens price accordingly on time shift", async () => {
-      await new Promise((resolve) => setTimeout(resolve, 42000));
-      const data = await program.methods
-        .calculatePrice(new anchor.BN(25))
-        .accounts({
-          presaleAccount: presalePDA,
-        })
-        .view();
+      let isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(1), false, null, null)
+          .accounts({
+            user: user.publicKey,
+            userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            userUsdtAta: userATA,
+            presaleAccount: presalePDA,
+            paymentWalletUsdt: paymentWalletUSDT,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: tokenProgram,
+          })
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6035, "Allowlist check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      await program.methods
+        .changeRoundMerkleRoots([])
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+    });
+
+    it("Should let allowlisted users buy only up to their allocation", async () => {
+      const allowlistLeaf = (
+        wallet: anchor.web3.PublicKey,
+        allocation: number
+      ) =>
+        Buffer.from(
+          keccak_256(
+            Buffer.concat([
+              wallet.toBuffer(),
+              new anchor.BN(allocation).toArrayLike(Buffer, "le", 16),
+            ])
+          )
+        );
+      const userLeaf = allowlistLeaf(user.publicKey, 30);
+      const user2Leaf = allowlistLeaf(user2.publicKey, 0);
+      const root = Buffer.from(
+        keccak_256(
+          Buffer.compare(userLeaf, user2Leaf) <= 0
+            ? Buffer.concat([userLeaf, user2Leaf])
+            : Buffer.concat([user2Leaf, userLeaf])
+        )
+      );
+      const allowlistProof = {
+        allocation: new anchor.BN(30),
+        proof: [Array.from(user2Leaf)],
+      };
+      const buyAccounts = {
+        user: user.publicKey,
+        userAccount: userPDA,
+        denylistEntry: userDenylistPDA,
+        beneficiaryDenylistEntry: userDenylistPDA,
+        userUsdtAta: userATA,
+        presaleAccount: presalePDA,
+        paymentWalletUsdt: paymentWalletUSDT,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: tokenProgram,
+      };
+
+      await program.methods
+        .changeRoundMerkleRoots([Array.from(root)])
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      // 25 tokens are already bought in this round, 5 more fit the allocation
+      const { failed } = await simulate([
+        await program.methods
+          .buyWithUsdt(new anchor.BN(5), false, allowlistProof, null)
+          .accounts(buyAccounts)
+          .instruction(),
+      ]);
+      assert(!failed, "Allowlisted buy failed");
+
+      let isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(6), false, allowlistProof, null)
+          .accounts(buyAccounts)
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6036,
+          "Allowlist allocation check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      await program.methods
+        .changeRoundMerkleRoots([])
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+    });
+
+    it("Should not let users buy without a valid KYC attestation", async () => {
+      const kycAttester = anchor.web3.Keypair.generate();
+      await program.methods
+        .changeKycAttester(kycAttester.publicKey)
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const buyAccounts = {
+        user: user.publicKey,
+        userAccount: userPDA,
+        denylistEntry: userDenylistPDA,
+        beneficiaryDenylistEntry: userDenylistPDA,
+        userUsdtAta: userATA,
+        presaleAccount: presalePDA,
+        paymentWalletUsdt: paymentWalletUSDT,
+        systemProgram: SystemProgram.programId,
+        tokenProgram: tokenProgram,
+        instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+      };
+
+      let isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(1), false, null, null)
+          .accounts(buyAccounts)
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6037, "KYC check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      let slot = await provider.connection.getSlot();
+      currentTime = await provider.connection.getBlockTime(slot);
+      const expiry = Buffer.alloc(16);
+      expiry.writeBigUInt64LE(BigInt(currentTime - 10));
+      const message = Buffer.concat([
+        user.publicKey.toBuffer(),
+        presalePDA.toBuffer(),
+        expiry,
+        Buffer.from([1]),
+      ]);
+
+      isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(1), false, null, null)
+          .accounts(buyAccounts)
+          .preInstructions([
+            anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+              privateKey: kycAttester.secretKey,
+              message,
+            }),
+          ])
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6039, "KYC expiry check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      await program.methods
+        .changeKycAttester(anchor.web3.PublicKey.default)
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+    });
+
+    it("Should not let denylisted users buy", async () => {
+      await program.methods
+        .addDenylistUser(user.publicKey, 1)
+        .accounts({
+          authority: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          denylistEntry: userDenylistPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+      let isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(1), false, null, null)
+          .accounts({
+            user: user.publicKey,
+            userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            userUsdtAta: userATA,
+            presaleAccount: presalePDA,
+            paymentWalletUsdt: paymentWalletUSDT,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: tokenProgram,
+          })
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6040, "Denylist check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      await program.methods
+        .releaseDenylistUser(user.publicKey)
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          denylistEntry: userDenylistPDA,
+        })
+        .rpc();
+    });
+
+    it("Should not let users buy for a denylisted beneficiary", async () => {
+      await program.methods
+        .addDenylistUser(user2.publicKey, 1)
+        .accounts({
+          authority: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          denylistEntry: user2DenylistPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+      let isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(1), false, null, user2.publicKey)
+          .accounts({
+            user: user.publicKey,
+            userAccount: userPDA2,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: user2DenylistPDA,
+            userUsdtAta: userATA,
+            presaleAccount: presalePDA,
+            paymentWalletUsdt: paymentWalletUSDT,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: tokenProgram,
+          })
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6040, "Beneficiary check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      await program.methods
+        .releaseDenylistUser(user2.publicKey)
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          denylistEntry: user2DenylistPDA,
+        })
+        .rpc();
+    });
+
+    it("Should register referrers and reject self referrals", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .changeReferralReward(10001, { saleToken: {} })
+          .accounts({
+            presaleAccount: presalePDA,
+            owner: provider.wallet.publicKey,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6041, "Reward bps check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      await program.methods
+        .changeReferralReward(500, { saleToken: {} })
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      await program.methods
+        .registerReferrer()
+        .accounts({
+          referrer: user2.publicKey,
+          presaleAccount: presalePDA,
+          referrerAccount: user2ReferrerPDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user2])
+        .rpc();
+
+      const referrerAccountData = await program.account.referrerAccount.fetch(
+        user2ReferrerPDA
+      );
+      assert(
+        referrerAccountData.referrer.equals(user2.publicKey),
+        "Referrer registration failed"
+      );
+
+      isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(1), false, null, null)
+          .accounts({
+            user: user2.publicKey,
+            userAccount: userPDA2,
+            denylistEntry: user2DenylistPDA,
+            beneficiaryDenylistEntry: user2DenylistPDA,
+            userUsdtAta: user2ATA,
+            presaleAccount: presalePDA,
+            paymentWalletUsdt: paymentWalletUSDT,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: tokenProgram,
+            referrerAccount: user2ReferrerPDA,
+          })
+          .signers([user2])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6042,
+          "Self referral check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(1), false, null, user.publicKey)
+          .accounts({
+            user: user2.publicKey,
+            userAccount: userPDA,
+            denylistEntry: user2DenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            userUsdtAta: user2ATA,
+            presaleAccount: presalePDA,
+            paymentWalletUsdt: paymentWalletUSDT,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: tokenProgram,
+            referrerAccount: user2ReferrerPDA,
+          })
+          .signers([user2])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6042,
+          "Payer referral check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not allow unsorted bonus tiers", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .changeBonusTiers([
+            { minUsd: new anchor.BN(50_000_000_000_000), bonusBps: 1000 },
+            { minUsd: new anchor.BN(10_000_000_000_000), bonusBps: 500 },
+          ])
+          .accounts({
+            presaleAccount: presalePDA,
+            owner: provider.wallet.publicKey,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6043, "Bonus tiers check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should allow the owner to set bonus tiers", async () => {
+      await program.methods
+        .changeBonusTiers([
+          { minUsd: new anchor.BN(250_000_000_000), bonusBps: 400 },
+        ])
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(
+        presaleAccountData.bonusTiers[0].bonusBps == 400,
+        "Bonus tiers update failed"
+      );
+    });
+
+    it("Should not allow early bird bonus above 10000 bps", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .changeEarlyBirdBonus(10001, new anchor.BN(3600))
+          .accounts({
+            presaleAccount: presalePDA,
+            owner: provider.wallet.publicKey,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6044, "Early bird check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not allow discount codes that already expired", async () => {
+      const codeHash = Array(32).fill(7);
+      const [discountCodePDA] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("discount_code"), Buffer.from(codeHash)],
+        program.programId
+      );
+
+      let isError = false;
+      try {
+        await program.methods
+          .createDiscountCode(
+            codeHash,
+            1000,
+            new anchor.BN(10),
+            new anchor.BN(1),
+            new anchor.BN(1)
+          )
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            discountCode: discountCodePDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6045,
+          "Discount code check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let users redeem a closed discount code", async () => {
+      let slot = await provider.connection.getSlot();
+      currentTime = await provider.connection.getBlockTime(slot);
+      const codeHash = Array(32).fill(11);
+      const [discountCodePDA] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("discount_code"), Buffer.from(codeHash)],
+        program.programId
+      );
+      const [discountRedemptionPDA] =
+        anchor.web3.PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("discount_redemption"),
+            discountCodePDA.toBytes(),
+            user.publicKey.toBytes(),
+          ],
+          program.programId
+        );
+
+      await program.methods
+        .createDiscountCode(
+          codeHash,
+          1000,
+          new anchor.BN(0),
+          new anchor.BN(0),
+          new anchor.BN(currentTime + 3600)
+        )
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          discountCode: discountCodePDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      await program.methods
+        .closeDiscountCode(codeHash)
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          discountCode: discountCodePDA,
+        })
+        .rpc();
+
+      const discountCode = await program.account.discountCode.fetchNullable(
+        discountCodePDA
+      );
+      assert(discountCode == null, "Discount code close failed");
+
+      let isError = false;
+      try {
+        await program.methods
+          .buyWithUsdt(new anchor.BN(1), false, null, null)
+          .accounts({
+            user: user.publicKey,
+            userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            userUsdtAta: userATA,
+            presaleAccount: presalePDA,
+            paymentWalletUsdt: paymentWalletUSDT,
+            systemProgram: SystemProgram.programId,
+            tokenProgram: tokenProgram,
+            discountCode: discountCodePDA,
+            discountRedemption: discountRedemptionPDA,
+          })
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 3012,
+          "Closed discount code check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let users record external purchases", async () => {
+      const externalRef = Array(32).fill(3);
+      const [externalPurchasePDA] =
+        anchor.web3.PublicKey.findProgramAddressSync(
+          [Buffer.from("external_purchase"), Buffer.from(externalRef)],
+          program.programId
+        );
+
+      let isError = false;
+      try {
+        await program.methods
+          .recordExternalPurchase(
+            user.publicKey,
+            new anchor.BN(1),
+            new anchor.BN(1000),
+            externalRef
+          )
+          .accounts({
+            authority: user.publicKey,
+            presaleAccount: presalePDA,
+            userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            externalPurchase: externalPurchasePDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6015, "Access check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let external purchases exceed the hard cap", async () => {
+      const externalRef = Array(32).fill(4);
+      const [externalPurchasePDA] =
+        anchor.web3.PublicKey.findProgramAddressSync(
+          [Buffer.from("external_purchase"), Buffer.from(externalRef)],
+          program.programId
+        );
+
+      let isError = false;
+      try {
+        await program.methods
+          .recordExternalPurchase(
+            user.publicKey,
+            new anchor.BN(1),
+            hardCapUsd,
+            externalRef
+          )
+          .accounts({
+            authority: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            externalPurchase: externalPurchasePDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6034, "Hard cap check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should allow the owner to import private allocations", async () => {
+      const investor = anchor.web3.Keypair.generate();
+      const [investorPDA] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("user_account"), investor.publicKey.toBytes()],
+        program.programId
+      );
+
+      await program.methods
+        .importAllocations([
+          {
+            wallet: investor.publicKey,
+            amount: new anchor.BN(10),
+            vestingClass: 1,
+          },
+        ])
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          systemProgram: SystemProgram.programId,
+        })
+        .remainingAccounts([
+          { pubkey: investorPDA, isWritable: true, isSigner: false },
+        ])
+        .rpc();
+
+      const investorAccountData = await program.account.userAccount.fetch(
+        investorPDA
+      );
+      assert(
+        investorAccountData.privateAmount.toString() == "10",
+        "Private allocation import failed"
+      );
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(
+        presaleAccountData.privateAllocated.toString() == "10",
+        "Private allocated update failed"
+      );
+    });
+
+    it("Should import allocations into user accounts funded with dust", async () => {
+      const investor = anchor.web3.Keypair.generate();
+      const [investorPDA] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("user_account"), investor.publicKey.toBytes()],
+        program.programId
+      );
+
+      const { failed, accountData } = await simulate(
+        [
+          SystemProgram.transfer({
+            fromPubkey: provider.wallet.publicKey,
+            toPubkey: investorPDA,
+            lamports:
+              await provider.connection.getMinimumBalanceForRentExemption(0),
+          }),
+          await program.methods
+            .importAllocations([
+              {
+                wallet: investor.publicKey,
+                amount: new anchor.BN(10),
+                vestingClass: 1,
+              },
+            ])
+            .accounts({
+              owner: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              systemProgram: SystemProgram.programId,
+            })
+            .remainingAccounts([
+              { pubkey: investorPDA, isWritable: true, isSigner: false },
+            ])
+            .instruction(),
+        ],
+        [investorPDA]
+      );
+      assert(!failed, "Dust funded import failed");
+
+      const investorAccountData = program.coder.accounts.decode(
+        "UserAccount",
+        accountData[0]
+      );
+      assert(
+        investorAccountData.privateAmount.toString() == "10",
+        "Private allocation import failed"
+      );
+    });
+
+    it("Should not import allocations with a different vesting class", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .importAllocations([
+            {
+              wallet: user.publicKey,
+              amount: new anchor.BN(10),
+              vestingClass: 1,
+            },
+          ])
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .remainingAccounts([
+            { pubkey: userPDA, isWritable: true, isSigner: false },
+          ])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6052,
+          "Vesting class check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not allow the owner to adjust allocations during the sale", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .adjustAllocation(user.publicKey, new anchor.BN(10), 1)
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            userAccount: userPDA,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6018, "Status check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not allow the owner to move allocations during the sale", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .moveAllocation(user.publicKey, user2.publicKey, 1)
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            fromUserAccount: userPDA,
+            toUserAccount: userPDA2,
+            systemProgram: SystemProgram.programId,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6018, "Status check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let users to buy if presale is paused", async () => {
+      let isError = false;
+      await program.methods
+        .pausePresale()
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+      try {
+        await program.methods
+          .buyWithSol(new anchor.BN(10), false, null, null)
+          .accounts({
+            presaleAccount: presalePDA,
+            user: user.publicKey,
+            userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            paymentWallet: paymentWallet.publicKey,
+            systemProgram: SystemProgram.programId,
+            chainlinkFeed: chainlinkFeed,
+            chainlinkProgram: chainlinkProgram,
+          })
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6007,
+          "Presale pause check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let users to buy more than max tokens in single transaction", async () => {
+      let isError = false;
+      await program.methods
+        .unpausePresale()
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+      try {
+        await program.methods
+          .buyWithSol(new anchor.BN(100), false, null, null)
+          .accounts({
+            presaleAccount: presalePDA,
+            user: user.publicKey,
+            userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
+            paymentWallet: paymentWallet.publicKey,
+            systemProgram: SystemProgram.programId,
+            chainlinkFeed: chainlinkFeed,
+            chainlinkProgram: chainlinkProgram,
+          })
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6002, "Max tokens check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let owner change start time once presale starts", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .changeStartTime(new anchor.BN(100000000000000))
+          .accounts({
+            presaleAccount: presalePDA,
+            owner: provider.wallet.publicKey,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6001,
+          "Presale already started check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let owner change hard cap once presale starts", async () => {
+      let isError = false;
+      try {
+        await program.methods
+          .changeHardCapUsd(new anchor.BN(1_000_000_000_000))
+          .accounts({
+            presaleAccount: presalePDA,
+            owner: provider.wallet.publicKey,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6001,
+          "Presale already started check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should let users buy upto max tokens for another wallet", async () => {
+      const signature = await program.methods
+        .buyWithUsdt(new anchor.BN(50), false, null, user2.publicKey)
+        .accounts({
+          user: user.publicKey,
+          userAccount: userPDA2,
+          denylistEntry: userDenylistPDA,
+          beneficiaryDenylistEntry: user2DenylistPDA,
+          userUsdtAta: userATA,
+          presaleAccount: presalePDA,
+          paymentWalletUsdt: paymentWalletUSDT,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: tokenProgram,
+        })
+        .signers([user])
+        .rpc({ commitment: "confirmed" });
+      const transaction = await provider.connection.getTransaction(signature, {
+        commitment: "confirmed",
+        maxSupportedTransactionVersion: 0,
+      });
+      const eventParser = new anchor.EventParser(
+        program.programId,
+        program.coder
+      );
+      const purchase = [
+        ...eventParser.parseLogs(transaction.meta.logMessages),
+      ].find((event) => event.name == "TokensPurchased");
+      assert(
+        purchase.data.payer.equals(user.publicKey),
+        "Purchase payer mismatch"
+      );
+      assert(
+        purchase.data.beneficiary.equals(user2.publicKey),
+        "Purchase beneficiary mismatch"
+      );
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(
+        presaleAccountData.tokensSold.toString() == "75",
+        "Tokens sold update failed"
+      );
+      const userAccountData = await program.account.userAccount.fetch(userPDA2);
+      assert(
+        userAccountData.purchasedAmount.toString() == "50",
+        "User purchased amount update failed"
+      );
+      assert(
+        userAccountData.rentPayer.equals(user.publicKey),
+        "Rent payer update failed"
+      );
+    });
+
+    it("Should calculate tokens price accordingly on sellout", async () => {
+      const resp = await program.methods
+        .calculatePrice(new anchor.BN(50))
+        .accounts({
+          presaleAccount: presalePDA,
+        })
+        .view();
+      assert(
+        resp.priceInUsd.toString() == "75000000000",
+        "Price on sellout check failed"
+      );
+      assert(resp.newRound.toString() == "1", "New round suggest failed");
+    });
+
+    it("Should calculate tokens price accordingly on time shift", async () => {
+      await new Promise((resolve) => setTimeout(resolve, 42000));
+      const data = await program.methods
+        .calculatePrice(new anchor.BN(25))
+        .accounts({
+          presaleAccount: presalePDA,
+        })
+        .view();
       assert(data.newRound.toString() == "2", "Round calculation failed");
       assert(
         data.priceInUsd.toString() == "75000000000",
@@ -1118,10 +2361,12 @@ const test = async () => {
       let slot = await provider.connection.getSlot();
       currentTime = await provider.connection.getBlockTime(slot);
       await program.methods
-        .buyWithUsdt(new anchor.BN(25))
+        .buyWithUsdt(new anchor.BN(25), false, null, null)
         .accounts({
           user: user.publicKey,
           userAccount: userPDA,
+          denylistEntry: userDenylistPDA,
+          beneficiaryDenylistEntry: userDenylistPDA,
           userUsdtAta: userATA,
           presaleAccount: presalePDA,
           paymentWalletUsdt: paymentWalletUSDT,
@@ -1147,30 +2392,95 @@ const test = async () => {
       );
     });
 
+    it("Should let the owner roll unsold tokens over into the current round", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const presaleAta = await provider.connection.getTokenAccountBalance(
+        presaleSaleTokenATA
+      );
+      const unsold = presaleAccountData.unsoldTokens[0];
+
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .changeUnsoldPolicy({ rollover: {} })
+            .accounts({
+              presaleAccount: presalePDA,
+              owner: provider.wallet.publicKey,
+            })
+            .instruction(),
+          await program.methods
+            .rolloverUnsoldTokens(new anchor.BN(0))
+            .accounts({
+              presaleAccount: presalePDA,
+              owner: provider.wallet.publicKey,
+            })
+            .instruction(),
+        ],
+        [presalePDA, presaleSaleTokenATA]
+      );
+      assert(!failed, "Rollover failed");
+
+      const rollover = events.find(
+        (event) => event.name == "UnsoldTokensRolledOver"
+      );
+      assert(rollover.data.amount.eq(unsold), "Rollover event failed");
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert(
+        presaleAfter.currentTracker.eq(
+          presaleAccountData.currentTracker.sub(unsold)
+        ),
+        "Current tracker update failed"
+      );
+      assert(presaleAfter.unsoldTokens[0].isZero(), "Unsold tokens failed");
+      assert(
+        presaleAfter.tokensAdded.eq(presaleAccountData.tokensAdded),
+        "Tokens added update failed"
+      );
+      // Rolled over tokens stay deposited for the next buyers
+      assert(
+        String(spl.AccountLayout.decode(accountData[1]).amount) ==
+          presaleAta.value.amount,
+        "Presale ATA balance failed"
+      );
+    });
+
     it("Should allow users to buy tokens and switch rounds based sellout", async () => {
       let slot = await provider.connection.getSlot();
       currentTime = await provider.connection.getBlockTime(slot);
       let presaleAccountData = await program.account.presaleAccount.fetch(
         presalePDA
       );
+      const userAccountBefore = await program.account.userAccount.fetch(
+        userPDA
+      );
       await program.methods
-        .buyWithUsdt(new anchor.BN(50))
+        .buyWithUsdt(new anchor.BN(50), false, null, null)
         .accounts({
           user: user.publicKey,
           userAccount: userPDA,
+          denylistEntry: userDenylistPDA,
+          beneficiaryDenylistEntry: userDenylistPDA,
           userUsdtAta: userATA,
           presaleAccount: presalePDA,
           paymentWalletUsdt: paymentWalletUSDT,
           systemProgram: SystemProgram.programId,
           tokenProgram: tokenProgram,
+          referrerAccount: user2ReferrerPDA,
         })
         .signers([user])
         .rpc();
       await program.methods
-        .buyWithUsdt(new anchor.BN(50))
+        .buyWithUsdt(new anchor.BN(50), false, null, null)
         .accounts({
           user: user.publicKey,
           userAccount: userPDA,
+          denylistEntry: userDenylistPDA,
+          beneficiaryDenylistEntry: userDenylistPDA,
           userUsdtAta: userATA,
           presaleAccount: presalePDA,
           paymentWalletUsdt: paymentWalletUSDT,
@@ -1184,25 +2494,495 @@ const test = async () => {
       );
 
       assert(
-        presaleAccountData.currentRound.toString() == "3",
-        "Round update failed"
+        presaleAccountData.currentRound.toString() == "3",
+        "Round update failed"
+      );
+      assert(
+        presaleAccountData.tokensSold.toString() == "200",
+        "Tokens sold update failed"
+      );
+      assert(
+        presaleAccountData.currentTracker.toString() == "325",
+        "Current tracker update failed"
+      );
+      assert(
+        presaleAccountData.referralTokensAllocated.toString() == "2",
+        "Referral tokens allocated update failed"
+      );
+
+      const referrerAccountData = await program.account.referrerAccount.fetch(
+        user2ReferrerPDA
+      );
+      assert(
+        referrerAccountData.rewardTokens.toString() == "2",
+        "Referral reward failed"
+      );
+
+      // The second buy fills the last 25 tokens of round 2 and 25 of round 3
+      const userAccountData = await program.account.userAccount.fetch(userPDA);
+      assert(
+        userAccountData.roundPurchased[2]
+          .sub(userAccountBefore.roundPurchased[2])
+          .toString() == "75",
+        "Round purchased update failed"
+      );
+      assert(
+        userAccountData.roundPurchased[3]
+          .sub(userAccountBefore.roundPurchased[3])
+          .toString() == "25",
+        "Next round purchased update failed"
+      );
+    });
+
+    it("Should trim a buy crossing the hard cap and end the sale", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const externalRef = Array(32).fill(9);
+      const [externalPurchasePDA] =
+        anchor.web3.PublicKey.findProgramAddressSync(
+          [Buffer.from("external_purchase"), Buffer.from(externalRef)],
+          program.programId
+        );
+
+      // Leaves 100 USD under the cap, enough for 25 tokens of the last round
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .recordExternalPurchase(
+              user.publicKey,
+              new anchor.BN(1),
+              hardCapUsd
+                .sub(presaleAccountData.usdRaised)
+                .sub(new anchor.BN(100_000_000_000)),
+              externalRef
+            )
+            .accounts({
+              authority: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              userAccount: userPDA,
+              denylistEntry: userDenylistPDA,
+              externalPurchase: externalPurchasePDA,
+              systemProgram: SystemProgram.programId,
+            })
+            .instruction(),
+          await program.methods
+            .buyWithUsdt(new anchor.BN(70), false, null, null)
+            .accounts({
+              user: user.publicKey,
+              userAccount: userPDA,
+              denylistEntry: userDenylistPDA,
+              beneficiaryDenylistEntry: userDenylistPDA,
+              userUsdtAta: userATA,
+              presaleAccount: presalePDA,
+              paymentWalletUsdt: paymentWalletUSDT,
+              systemProgram: SystemProgram.programId,
+              tokenProgram: tokenProgram,
+            })
+            .instruction(),
+        ],
+        [presalePDA]
+      );
+      assert(!failed, "Hard cap buy failed");
+
+      const purchase = events.find((event) => event.name == "TokensPurchased");
+      assert(
+        purchase.data.requestedAmount.toString() == "70",
+        "Request failed"
+      );
+      assert(purchase.data.amount.toString() == "25", "Hard cap trim failed");
+      assert(
+        events.some((event) => event.name == "PresaleEnded"),
+        "Presale end event failed"
+      );
+
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert("ended" in presaleAfter.status, "Status end failed");
+      assert(presaleAfter.usdRaised.eq(hardCapUsd), "USD raised failed");
+      assert(
+        presaleAfter.tokensSold.eq(presaleAccountData.tokensSold.addn(26)),
+        "Tokens sold update failed"
+      );
+    });
+
+    it("Should apply discount codes before the hard cap and bonus tiers", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const codeHash = Array(32).fill(8);
+      const [discountCodePDA] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("discount_code"), Buffer.from(codeHash)],
+        program.programId
+      );
+      const [discountRedemptionPDA] =
+        anchor.web3.PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("discount_redemption"),
+            discountCodePDA.toBytes(),
+            user.publicKey.toBytes(),
+          ],
+          program.programId
+        );
+      const externalRef = Array(32).fill(10);
+      const [externalPurchasePDA] =
+        anchor.web3.PublicKey.findProgramAddressSync(
+          [Buffer.from("external_purchase"), Buffer.from(externalRef)],
+          program.programId
+        );
+      const usdLeft = new anchor.BN(250_000_000_000);
+
+      // 65 tokens cost 260 USD, above what is left under the cap and at the 250 USD
+      // bonus tier, but only 208 USD with the 20% discount
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .createDiscountCode(
+              codeHash,
+              2000,
+              new anchor.BN(0),
+              new anchor.BN(0),
+              new anchor.BN(currentTime + 3600)
+            )
+            .accounts({
+              owner: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              discountCode: discountCodePDA,
+              systemProgram: SystemProgram.programId,
+            })
+            .instruction(),
+          await program.methods
+            .recordExternalPurchase(
+              user.publicKey,
+              new anchor.BN(1),
+              hardCapUsd.sub(presaleAccountData.usdRaised).sub(usdLeft),
+              externalRef
+            )
+            .accounts({
+              authority: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              userAccount: userPDA,
+              denylistEntry: userDenylistPDA,
+              externalPurchase: externalPurchasePDA,
+              systemProgram: SystemProgram.programId,
+            })
+            .instruction(),
+          await program.methods
+            .buyWithUsdt(new anchor.BN(65), false, null, null)
+            .accounts({
+              user: user.publicKey,
+              userAccount: userPDA,
+              denylistEntry: userDenylistPDA,
+              beneficiaryDenylistEntry: userDenylistPDA,
+              userUsdtAta: userATA,
+              presaleAccount: presalePDA,
+              paymentWalletUsdt: paymentWalletUSDT,
+              systemProgram: SystemProgram.programId,
+              tokenProgram: tokenProgram,
+              discountCode: discountCodePDA,
+              discountRedemption: discountRedemptionPDA,
+            })
+            .instruction(),
+        ],
+        [presalePDA]
+      );
+      assert(!failed, "Discounted buy failed");
+
+      const purchase = events.find((event) => event.name == "TokensPurchased");
+      assert(purchase.data.amount.toString() == "65", "Hard cap trim failed");
+      assert(purchase.data.bonusAmount.toString() == "0", "Bonus check failed");
+      assert(
+        purchase.data.priceInUsd.toString() == "208000000000",
+        "Discounted price failed"
+      );
+      const redemption = events.find(
+        (event) => event.name == "DiscountCodeRedeemed"
+      );
+      assert(
+        redemption.data.discountUsd.toString() == "52000000000",
+        "Discount failed"
+      );
+
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert(
+        presaleAfter.usdRaised.eq(
+          hardCapUsd.sub(usdLeft).add(new anchor.BN(208_000_000_000))
+        ),
+        "USD raised update failed"
+      );
+    });
+
+    it("Should partially fill a buy above the remaining tokens", async () => {
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .buyWithUsdt(new anchor.BN(80), true, null, null)
+            .accounts({
+              user: user.publicKey,
+              userAccount: userPDA,
+              denylistEntry: userDenylistPDA,
+              beneficiaryDenylistEntry: userDenylistPDA,
+              userUsdtAta: userATA,
+              presaleAccount: presalePDA,
+              paymentWalletUsdt: paymentWalletUSDT,
+              systemProgram: SystemProgram.programId,
+              tokenProgram: tokenProgram,
+            })
+            .instruction(),
+        ],
+        [presalePDA]
+      );
+      assert(!failed, "Partial fill failed");
+
+      const purchase = events.find((event) => event.name == "TokensPurchased");
+      assert(
+        purchase.data.requestedAmount.toString() == "80",
+        "Request failed"
+      );
+      assert(purchase.data.amount.toString() == "75", "Partial fill failed");
+
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert(
+        presaleAfter.tokensSold.toString() == "275",
+        "Tokens sold update failed"
+      );
+      assert(
+        presaleAfter.currentTracker.toString() == "400",
+        "Current tracker update failed"
+      );
+    });
+
+    it("Should let anyone finalize the sale after the last round ends", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      let slot = await provider.connection.getSlot();
+      currentTime = await provider.connection.getBlockTime(slot);
+      const pastRounds = [
+        presaleAccountData.rounds[0],
+        presaleAccountData.rounds[1],
+        presaleAccountData.rounds[2].map(() => new anchor.BN(currentTime - 1)),
+      ];
+
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .changeRounds(pastRounds)
+            .accounts({
+              presaleAccount: presalePDA,
+              owner: provider.wallet.publicKey,
+            })
+            .instruction(),
+          await program.methods
+            .finalize()
+            .accounts({
+              presaleAccount: presalePDA,
+            })
+            .instruction(),
+        ],
+        [presalePDA]
+      );
+      assert(!failed, "Finalize after end time failed");
+
+      // 75 tokens of the last round are left when its time runs out
+      const ended = events.find((event) => event.name == "PresaleEnded");
+      assert(
+        ended.data.unsoldTokens.map(String).join() == "25,100,0,75",
+        "Unsold tokens event failed"
+      );
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert("ended" in presaleAfter.status, "Status end failed");
+      assert(
+        presaleAfter.unsoldTokens.map(String).join() == "25,100,0,75",
+        "Unsold tokens update failed"
+      );
+    });
+
+    // Buys the last 75 tokens, ends and finalizes the sale and deposits enough
+    // sale tokens for everything owed plus the 125 left unsold
+    const endSaleInstructions = async () => [
+      await program.methods
+        .buyWithUsdt(new anchor.BN(75), false, null, null)
+        .accounts({
+          user: user.publicKey,
+          userAccount: userPDA,
+          denylistEntry: userDenylistPDA,
+          beneficiaryDenylistEntry: userDenylistPDA,
+          userUsdtAta: userATA,
+          presaleAccount: presalePDA,
+          paymentWalletUsdt: paymentWalletUSDT,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: tokenProgram,
+        })
+        .instruction(),
+      await program.methods
+        .incrementCurrentRound()
+        .accounts({
+          user: admin.publicKey,
+          presaleAccount: presalePDA,
+        })
+        .instruction(),
+      await program.methods
+        .finalizePresale()
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .instruction(),
+      await program.methods
+        .depositSaleTokens(new anchor.BN(400))
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          ownerAta: ownerSaleTokenATA,
+          presaleAta: presaleSaleTokenATA,
+          saleToken: saleToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .instruction(),
+    ];
+
+    it("Should let the owner burn unsold tokens", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const presaleAta = await provider.connection.getTokenAccountBalance(
+        presaleSaleTokenATA
+      );
+      const mint = await spl.getMint(provider.connection, saleToken);
+
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .changeUnsoldPolicy({ burn: {} })
+            .accounts({
+              presaleAccount: presalePDA,
+              owner: provider.wallet.publicKey,
+            })
+            .instruction(),
+          ...(await endSaleInstructions()),
+          await program.methods
+            .burnUnsoldTokens()
+            .accounts({
+              owner: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              presaleAta: presaleSaleTokenATA,
+              saleToken: saleToken,
+              tokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .instruction(),
+        ],
+        [presalePDA, presaleSaleTokenATA, saleToken]
+      );
+      assert(!failed, "Burn unsold tokens failed");
+
+      const burned = events.find((event) => event.name == "UnsoldTokensBurned");
+      assert(burned.data.amount.toString() == "125", "Burn event failed");
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert(
+        presaleAfter.tokensAdded.eq(
+          presaleAccountData.tokensAdded.add(new anchor.BN(275))
+        ),
+        "Tokens added update failed"
       );
       assert(
-        presaleAccountData.tokensSold.toString() == "200",
-        "Tokens sold update failed"
+        presaleAfter.unsoldTokens.every((unsold) => unsold.isZero()),
+        "Unsold tokens update failed"
       );
       assert(
-        presaleAccountData.currentTracker.toString() == "325",
-        "Current tracker update failed"
+        spl.AccountLayout.decode(accountData[1]).amount ==
+          BigInt(presaleAta.value.amount) + BigInt(275000000),
+        "Presale ATA balance failed"
+      );
+      assert(
+        spl.MintLayout.decode(accountData[2]).supply ==
+          mint.supply - BigInt(125000000),
+        "Sale token supply failed"
+      );
+    });
+
+    it("Should let the owner withdraw unsold tokens", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const presaleAta = await provider.connection.getTokenAccountBalance(
+        presaleSaleTokenATA
+      );
+      const ownerAta = await provider.connection.getTokenAccountBalance(
+        ownerSaleTokenATA
+      );
+
+      const { failed, events, accountData } = await simulate(
+        [
+          ...(await endSaleInstructions()),
+          await program.methods
+            .withdrawUnsoldTokens()
+            .accounts({
+              owner: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              presaleAta: presaleSaleTokenATA,
+              ownerAta: ownerSaleTokenATA,
+              saleToken: saleToken,
+              tokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .instruction(),
+        ],
+        [presalePDA, presaleSaleTokenATA, ownerSaleTokenATA]
+      );
+      assert(!failed, "Withdraw unsold tokens failed");
+
+      const withdrawn = events.find(
+        (event) => event.name == "UnsoldTokensWithdrawn"
+      );
+      assert(
+        withdrawn.data.amount.toString() == "125",
+        "Withdraw event failed"
+      );
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert(
+        presaleAfter.tokensAdded.eq(
+          presaleAccountData.tokensAdded.add(new anchor.BN(275))
+        ),
+        "Tokens added update failed"
+      );
+      assert(
+        spl.AccountLayout.decode(accountData[1]).amount ==
+          BigInt(presaleAta.value.amount) + BigInt(275000000),
+        "Presale ATA balance failed"
+      );
+      // 400 deposited and 125 returned
+      assert(
+        spl.AccountLayout.decode(accountData[2]).amount ==
+          BigInt(ownerAta.value.amount) - BigInt(275000000),
+        "Owner ATA balance failed"
       );
     });
 
     it("Should allow to buy remaining tokens and finish presale", async () => {
       await program.methods
-        .buyWithUsdt(new anchor.BN(75))
+        .buyWithUsdt(new anchor.BN(75), false, null, null)
         .accounts({
           user: user.publicKey,
           userAccount: userPDA,
+          denylistEntry: userDenylistPDA,
+          beneficiaryDenylistEntry: userDenylistPDA,
           userUsdtAta: userATA,
           presaleAccount: presalePDA,
           paymentWalletUsdt: paymentWalletUSDT,
@@ -1222,16 +3002,22 @@ const test = async () => {
         presaleAccountData.currentTracker.toString() == "400",
         "Current tracker update failed"
       );
+      assert(
+        presaleAccountData.bonusTokensAllocated.toString() == "3",
+        "Bonus tokens allocated update failed"
+      );
     });
 
     it("Should not let user to buy more tokens once presale ends", async () => {
       let isError = false;
       try {
         await program.methods
-          .buyWithSol(new anchor.BN(1))
+          .buyWithSol(new anchor.BN(1), false, null, null)
           .accounts({
             user: user.publicKey,
             userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
+            beneficiaryDenylistEntry: userDenylistPDA,
             paymentWallet: paymentWallet.publicKey,
             presaleAccount: presalePDA,
             systemProgram: SystemProgram.programId,
@@ -1250,6 +3036,37 @@ const test = async () => {
   });
 
   describe("Claim", async () => {
+    it("Should let anyone finalize the sale once it sold out", async () => {
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .finalize()
+            .accounts({
+              presaleAccount: presalePDA,
+            })
+            .instruction(),
+        ],
+        [presalePDA]
+      );
+      assert(!failed, "Finalize on sellout failed");
+
+      const ended = events.find((event) => event.name == "PresaleEnded");
+      assert(ended.data.tokensSold.toString() == "275", "Tokens sold failed");
+      assert(
+        ended.data.unsoldTokens.map(String).join() == "25,100,0,0",
+        "Unsold tokens event failed"
+      );
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert("ended" in presaleAfter.status, "Status end failed");
+      assert(
+        presaleAfter.unsoldTokens.map(String).join() == "25,100,0,0",
+        "Unsold tokens update failed"
+      );
+    });
+
     it("Should allow the admin to increment round", async () => {
       let presaleAccountData = await program.account.presaleAccount.fetch(
         presalePDA
@@ -1266,6 +3083,430 @@ const test = async () => {
       presaleAccountData = await program.account.presaleAccount.fetch(
         presalePDA
       );
+      assert("ended" in presaleAccountData.status, "Status end failed");
+    });
+
+    it("Should let the owner adjust an allocation and its bonus", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const userAccountData = await program.account.userAccount.fetch(userPDA);
+      const newAmount = new anchor.BN(150);
+
+      const { failed, events, accountData } = await simulate(
+        [
+          await program.methods
+            .adjustAllocation(user.publicKey, newAmount, 1)
+            .accounts({
+              owner: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              userAccount: userPDA,
+            })
+            .instruction(),
+        ],
+        [presalePDA, userPDA]
+      );
+      assert(!failed, "Adjust allocation failed");
+
+      const adjusted = events.find(
+        (event) => event.name == "AllocationAdjusted"
+      );
+      assert(
+        adjusted.data.newPurchasedAmount.toString() == "150",
+        "Adjusted event failed"
+      );
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      const userAfter = program.coder.accounts.decode(
+        "UserAccount",
+        accountData[1]
+      );
+      const scaledBonus = userAccountData.bonusAmount
+        .mul(newAmount)
+        .div(userAccountData.purchasedAmount);
+      assert(
+        userAfter.purchasedAmount.eq(newAmount),
+        "Purchased amount update failed"
+      );
+      assert(userAfter.bonusAmount.eq(scaledBonus), "Bonus update failed");
+      assert(
+        presaleAfter.tokensSold.eq(
+          presaleAccountData.tokensSold
+            .sub(userAccountData.purchasedAmount)
+            .add(newAmount)
+        ),
+        "Tokens sold update failed"
+      );
+      assert(
+        presaleAfter.bonusTokensAllocated.eq(
+          presaleAccountData.bonusTokensAllocated
+            .sub(userAccountData.bonusAmount)
+            .add(scaledBonus)
+        ),
+        "Bonus tokens allocated update failed"
+      );
+    });
+
+    it("Should let the owner move an allocation to a new wallet", async () => {
+      const newWallet = anchor.web3.Keypair.generate();
+      const [newUserPDA] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("user_account"), newWallet.publicKey.toBytes()],
+        program.programId
+      );
+      const user2AccountData = await program.account.userAccount.fetch(
+        userPDA2
+      );
+
+      const { failed, accountData } = await simulate(
+        [
+          await program.methods
+            .moveAllocation(user2.publicKey, newWallet.publicKey, 1)
+            .accounts({
+              owner: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              fromUserAccount: userPDA2,
+              toUserAccount: newUserPDA,
+              systemProgram: SystemProgram.programId,
+            })
+            .instruction(),
+        ],
+        [userPDA2, newUserPDA]
+      );
+      assert(!failed, "Move allocation failed");
+
+      const fromAfter = program.coder.accounts.decode(
+        "UserAccount",
+        accountData[0]
+      );
+      const toAfter = program.coder.accounts.decode(
+        "UserAccount",
+        accountData[1]
+      );
+      assert(fromAfter.purchasedAmount.isZero(), "Source allocation failed");
+      assert(
+        toAfter.purchasedAmount.eq(user2AccountData.purchasedAmount),
+        "Moved allocation failed"
+      );
+      assert(
+        toAfter.rentPayer.toBase58() == provider.wallet.publicKey.toBase58(),
+        "Rent payer update failed"
+      );
+
+      const { errorCode } = await simulate([
+        await program.methods
+          .moveAllocation(user.publicKey, user.publicKey, 1)
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            fromUserAccount: userPDA,
+            toUserAccount: userPDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .instruction(),
+      ]);
+      assert(errorCode == 6050, "Move allocation check failed");
+    });
+
+    it("Should not allow owner to start claim before finalizing", async () => {
+      let isError = false;
+
+      try {
+        await program.methods
+          .startClaim(new anchor.BN(currentTime + 600), new anchor.BN(275))
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            ownerAta: ownerSaleTokenATA,
+            presaleAta: presaleSaleTokenATA,
+            saleToken: saleToken,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6018, "Status check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not let users transfer allocations when disabled", async () => {
+      await program.methods
+        .changeAllocationTransfers(true)
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      let isError = false;
+      try {
+        await program.methods
+          .transferAllocation(user2.publicKey)
+          .accounts({
+            user: user.publicKey,
+            presaleAccount: presalePDA,
+            userAccount: userPDA,
+            toUserAccount: userPDA2,
+            denylistEntry: userDenylistPDA,
+            toDenylistEntry: user2DenylistPDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([user])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6051,
+          "Transfer flag check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+
+      await program.methods
+        .changeAllocationTransfers(false)
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+    });
+
+    it("Should not let users transfer allocations across vesting classes", async () => {
+      const investor = anchor.web3.Keypair.generate();
+      const [investorPDA] = anchor.web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("user_account"), investor.publicKey.toBytes()],
+        program.programId
+      );
+      const [investorDenylistPDA] =
+        anchor.web3.PublicKey.findProgramAddressSync(
+          [Buffer.from("denylist"), investor.publicKey.toBytes()],
+          program.programId
+        );
+
+      const { errorCode } = await simulate([
+        await program.methods
+          .importAllocations([
+            {
+              wallet: investor.publicKey,
+              amount: new anchor.BN(10),
+              vestingClass: 2,
+            },
+          ])
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .remainingAccounts([
+            { pubkey: investorPDA, isWritable: true, isSigner: false },
+          ])
+          .instruction(),
+        await program.methods
+          .transferAllocation(investor.publicKey)
+          .accounts({
+            user: user.publicKey,
+            presaleAccount: presalePDA,
+            userAccount: userPDA,
+            toUserAccount: investorPDA,
+            denylistEntry: userDenylistPDA,
+            toDenylistEntry: investorDenylistPDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .instruction(),
+      ]);
+      assert(errorCode == 6052, "Vesting class check failed");
+    });
+
+    it("Should allow the owner to finalize presale", async () => {
+      await program.methods
+        .finalizePresale()
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert("finalized" in presaleAccountData.status, "Finalize failed");
+    });
+
+    it("Should not allow owner to start claim with mint without mint authority", async () => {
+      let isError = false;
+
+      try {
+        await program.methods
+          .startClaimWithMint(new anchor.BN(currentTime + 600))
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            saleToken: saleToken,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 2016,
+          "Mint authority check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not allow owner to start claim with mint with another sale token", async () => {
+      let isError = false;
+
+      const otherToken = await createMint(
+        provider.connection,
+        tempWallet,
+        presalePDA,
+        null,
+        6,
+        anchor.web3.Keypair.generate(),
+        { commitment: "confirmed" },
+        TOKEN_PROGRAM_ID
+      );
+
+      try {
+        await program.methods
+          .startClaimWithMint(new anchor.BN(currentTime + 600))
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            saleToken: otherToken,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6030,
+          "Sale token mismatch check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should mint tokens on claim when claiming with mint", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const [user2ClaimWhitelistPDA] =
+        anchor.web3.PublicKey.findProgramAddressSync(
+          [Buffer.from("claim_whitelist"), user2.publicKey.toBytes()],
+          program.programId
+        );
+
+      const { failed, accountData } = await simulate(
+        [
+          spl.createSetAuthorityInstruction(
+            saleToken,
+            tempWallet.publicKey,
+            spl.AuthorityType.MintTokens,
+            presalePDA
+          ),
+          await program.methods
+            .startClaimWithMint(
+              presaleAccountData.rounds[2][3].add(new anchor.BN(30))
+            )
+            .accounts({
+              owner: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              saleToken: saleToken,
+            })
+            .instruction(),
+          await program.methods
+            .addClaimWhitelistUser(user2.publicKey)
+            .accounts({
+              owner: provider.wallet.publicKey,
+              presaleAccount: presalePDA,
+              claimWhitelistEntry: user2ClaimWhitelistPDA,
+              systemProgram: SystemProgram.programId,
+            })
+            .instruction(),
+          await program.methods
+            .claim()
+            .accounts({
+              user: user2.publicKey,
+              presaleAccount: presalePDA,
+              userAccount: userPDA2,
+              rentPayer: user.publicKey,
+              denylistEntry: user2DenylistPDA,
+              presaleAta: null,
+              userAta: user2SaleTokenATA,
+              claimWhitelistEntry: user2ClaimWhitelistPDA,
+              saleToken: saleToken,
+              associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+              tokenProgram: TOKEN_PROGRAM_ID,
+              systemProgram: SystemProgram.programId,
+            })
+            .instruction(),
+        ],
+        [presalePDA, user2SaleTokenATA, saleToken]
+      );
+      assert(!failed, "Claim with mint failed");
+
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert(presaleAfter.mintOnClaim, "Mint on claim update failed");
+      assert(
+        presaleAfter.tokensClaimed.toString() == "75",
+        "Tokens claimed update failed"
+      );
+      assert(
+        String(spl.AccountLayout.decode(accountData[1]).amount) == "50000000",
+        "Token mint on claim failed"
+      );
+      const mintAfter = spl.MintLayout.decode(accountData[2]);
+      assert(
+        mintAfter.mintAuthority.toBase58() == presalePDA.toBase58(),
+        "Mint authority update failed"
+      );
+    });
+
+    it("Should not allow owner to revoke mint authority with unclaimed tokens", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+
+      const { errorCode } = await simulate([
+        spl.createSetAuthorityInstruction(
+          saleToken,
+          tempWallet.publicKey,
+          spl.AuthorityType.MintTokens,
+          presalePDA
+        ),
+        await program.methods
+          .startClaimWithMint(
+            presaleAccountData.rounds[2][3].add(new anchor.BN(30))
+          )
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            saleToken: saleToken,
+          })
+          .instruction(),
+        await program.methods
+          .revokeMintAuthority()
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            saleToken: saleToken,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .instruction(),
+      ]);
+      assert(errorCode == 6019, "Unclaimed tokens check failed");
     });
 
     it("Should not allow non-owner to start claim", async () => {
@@ -1286,7 +3527,7 @@ const test = async () => {
 
       try {
         await program.methods
-          .startClaim(new anchor.BN(currentTime + 600), new anchor.BN(275), 9)
+          .startClaim(new anchor.BN(currentTime + 600), new anchor.BN(275))
           .accounts({
             owner: newOwnerAddress.publicKey,
             presaleAccount: presalePDA,
@@ -1313,9 +3554,10 @@ const test = async () => {
     it("Should not allow owner to start claim with lesser tokens", async () => {
       let isError = false;
 
+      // 25 tokens are deposited, 275 sold plus 10 imported, 2 referral and 3 bonus owed
       try {
         await program.methods
-          .startClaim(new anchor.BN(currentTime + 600), new anchor.BN(274), 9)
+          .startClaim(new anchor.BN(currentTime + 600), new anchor.BN(264))
           .accounts({
             owner: provider.wallet.publicKey,
             presaleAccount: presalePDA,
@@ -1340,7 +3582,7 @@ const test = async () => {
 
       try {
         await program.methods
-          .startClaim(new anchor.BN(1), new anchor.BN(275), 9)
+          .startClaim(new anchor.BN(1), new anchor.BN(275))
           .accounts({
             owner: provider.wallet.publicKey,
             presaleAccount: presalePDA,
@@ -1360,18 +3602,38 @@ const test = async () => {
       assert(isError, "Test case failed");
     });
 
-    it("Should not allow owner to start claim with 0 decimals", async () => {
+    it("Should not allow owner to start claim with another sale token", async () => {
       let isError = false;
 
+      const otherToken = await createMint(
+        provider.connection,
+        tempWallet,
+        tempWallet.publicKey,
+        tempWallet.publicKey,
+        6,
+        anchor.web3.Keypair.generate(),
+        { commitment: "confirmed" },
+        TOKEN_PROGRAM_ID
+      );
+      const ownerOtherTokenATA = await createAccountAndFund(
+        provider.wallet,
+        otherToken,
+        1000000000000
+      );
+
       try {
         await program.methods
-          .startClaim(new anchor.BN(currentTime + 600), new anchor.BN(275), 0)
+          .startClaim(new anchor.BN(currentTime + 600), new anchor.BN(275))
           .accounts({
             owner: provider.wallet.publicKey,
             presaleAccount: presalePDA,
-            ownerAta: ownerSaleTokenATA,
-            presaleAta: presaleSaleTokenATA,
-            saleToken: saleToken,
+            ownerAta: ownerOtherTokenATA,
+            presaleAta: spl.getAssociatedTokenAddressSync(
+              otherToken,
+              presalePDA,
+              true
+            ),
+            saleToken: otherToken,
             associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
             tokenProgram: TOKEN_PROGRAM_ID,
             systemProgram: SystemProgram.programId,
@@ -1380,8 +3642,8 @@ const test = async () => {
       } catch (error) {
         let err = error as anchor.AnchorError;
         assert(
-          err.error.errorCode.number == 6012,
-          "Zero decimals check failed"
+          err.error.errorCode.number == 6030,
+          "Sale token mismatch check failed"
         );
         isError = true;
       }
@@ -1396,8 +3658,7 @@ const test = async () => {
       await program.methods
         .startClaim(
           new anchor.BN(presaleAccountData.rounds[2][3].add(new anchor.BN(30))),
-          new anchor.BN(275),
-          6
+          new anchor.BN(265)
         )
         .accounts({
           owner: provider.wallet.publicKey,
@@ -1420,7 +3681,7 @@ const test = async () => {
         "Sale token update failed"
       );
       assert(
-        presaleAccountData.tokensAdded.toString() == "275",
+        presaleAccountData.tokensAdded.toString() == "290",
         "Tokens added update failed"
       );
       assert(
@@ -1447,6 +3708,7 @@ const test = async () => {
             user: user.publicKey,
             presaleAccount: presalePDA,
             userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
             presaleAta: presaleSaleTokenATA,
             userAta: userSaleTokenATA,
             saleToken: saleToken,
@@ -1482,6 +3744,7 @@ const test = async () => {
             user: user.publicKey,
             presaleAccount: presalePDA,
             userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
             presaleAta: presaleSaleTokenATA,
             userAta: userSaleTokenATA,
             saleToken: saleToken,
@@ -1502,21 +3765,39 @@ const test = async () => {
       assert(isError, "Test case failed");
     });
 
+    it("Should allow the owner to deposit sale tokens", async () => {
+      await program.methods
+        .depositSaleTokens(new anchor.BN(5))
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          ownerAta: ownerSaleTokenATA,
+          presaleAta: presaleSaleTokenATA,
+          saleToken: saleToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(
+        presaleAccountData.tokensAdded.toString() == "295",
+        "Tokens added update failed"
+      );
+    });
+
     it("Should let users claim when whitelisted", async () => {
       let slot = await provider.connection.getSlot();
       currentTime = await provider.connection.getBlockTime(slot);
 
       await program.methods
-        .changeClaimWhitelistUsers([
-          user.publicKey,
-          user.publicKey,
-          user.publicKey,
-          user.publicKey,
-          user.publicKey,
-        ])
+        .addClaimWhitelistUser(user.publicKey)
         .accounts({
           owner: provider.wallet.publicKey,
           presaleAccount: presalePDA,
+          claimWhitelistEntry: userClaimWhitelistPDA,
+          systemProgram: SystemProgram.programId,
         })
         .rpc();
 
@@ -1526,8 +3807,10 @@ const test = async () => {
           user: user.publicKey,
           presaleAccount: presalePDA,
           userAccount: userPDA,
+          denylistEntry: userDenylistPDA,
           presaleAta: presaleSaleTokenATA,
           userAta: userSaleTokenATA,
+          claimWhitelistEntry: userClaimWhitelistPDA,
           saleToken: saleToken,
           associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
           tokenProgram: TOKEN_PROGRAM_ID,
@@ -1540,7 +3823,23 @@ const test = async () => {
         userSaleTokenATA
       );
       if (info.value.uiAmount == null) throw new Error("No balance found");
-      assert(info.value.amount == "225000000", "Token claim failed");
+      assert(info.value.amount == "228000000", "Token claim failed");
+    });
+
+    it("Should let the owner remove a wallet from the claim whitelist", async () => {
+      await program.methods
+        .removeClaimWhitelistUser(user.publicKey)
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          claimWhitelistEntry: userClaimWhitelistPDA,
+        })
+        .rpc();
+
+      const entry = await program.account.claimWhitelistEntry.fetchNullable(
+        userClaimWhitelistPDA
+      );
+      assert(entry == null, "Claim whitelist removal failed");
     });
 
     it("Should not allow users to claim when paused", async () => {
@@ -1569,6 +3868,8 @@ const test = async () => {
             user: user2.publicKey,
             presaleAccount: presalePDA,
             userAccount: userPDA2,
+            rentPayer: user.publicKey,
+            denylistEntry: user2DenylistPDA,
             presaleAta: presaleSaleTokenATA,
             userAta: user2SaleTokenATA,
             saleToken: saleToken,
@@ -1586,7 +3887,87 @@ const test = async () => {
       assert(isError, "Test case failed");
     });
 
-    it("Should let user 2 claim", async () => {
+    it("Should let the owner reclaim a denylisted allocation", async () => {
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const instructions = [
+        await program.methods
+          .unpausePresale()
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+          })
+          .instruction(),
+        await program.methods
+          .addDenylistUser(user2.publicKey, 1)
+          .accounts({
+            authority: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            denylistEntry: user2DenylistPDA,
+            systemProgram: SystemProgram.programId,
+          })
+          .instruction(),
+        await program.methods
+          .reclaimDenylistedAllocation(user2.publicKey)
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            userAccount: userPDA2,
+            denylistEntry: user2DenylistPDA,
+          })
+          .instruction(),
+      ];
+
+      const { failed, accountData } = await simulate(instructions, [
+        presalePDA,
+        userPDA2,
+      ]);
+      assert(!failed, "Reclaim failed");
+
+      const presaleAfter = program.coder.accounts.decode(
+        "PresaleAccount",
+        accountData[0]
+      );
+      assert(
+        presaleAfter.tokensReclaimed
+          .sub(presaleAccountData.tokensReclaimed)
+          .toString() == "50",
+        "Tokens reclaimed update failed"
+      );
+      const userAfter = program.coder.accounts.decode(
+        "UserAccount",
+        accountData[1]
+      );
+      assert(
+        userAfter.reclaimedAmount.toString() == "50",
+        "Reclaimed amount update failed"
+      );
+
+      // The denylisted wallet can no longer claim
+      const { errorCode } = await simulate([
+        ...instructions,
+        await program.methods
+          .claim()
+          .accounts({
+            user: user2.publicKey,
+            presaleAccount: presalePDA,
+            userAccount: userPDA2,
+            rentPayer: user.publicKey,
+            denylistEntry: user2DenylistPDA,
+            presaleAta: presaleSaleTokenATA,
+            userAta: user2SaleTokenATA,
+            saleToken: saleToken,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
+          })
+          .instruction(),
+      ]);
+      assert(errorCode == 6040, "Denylist claim check failed");
+    });
+
+    it("Should let user 2 claim with a sponsored fee payer", async () => {
       await program.methods
         .unpausePresale()
         .accounts({
@@ -1594,12 +3975,18 @@ const test = async () => {
           presaleAccount: presalePDA,
         })
         .rpc();
+      const rentPayerBalance = await provider.connection.getBalance(
+        user.publicKey
+      );
       await program.methods
         .claim()
         .accounts({
           user: user2.publicKey,
+          feePayer: provider.wallet.publicKey,
           presaleAccount: presalePDA,
           userAccount: userPDA2,
+          rentPayer: user.publicKey,
+          denylistEntry: user2DenylistPDA,
           presaleAta: presaleSaleTokenATA,
           userAta: user2SaleTokenATA,
           saleToken: saleToken,
@@ -1615,6 +4002,11 @@ const test = async () => {
       );
       if (info.value.uiAmount == null) throw new Error("No balance found");
       assert(info.value.amount == "50000000", "Token claim failed");
+      assert(
+        (await provider.connection.getBalance(user.publicKey)) >
+          rentPayerBalance,
+        "Rent refund failed"
+      );
     });
 
     it("Should not allow users to claim after successfull claim", async () => {
@@ -1627,6 +4019,7 @@ const test = async () => {
             user: user.publicKey,
             presaleAccount: presalePDA,
             userAccount: userPDA,
+            denylistEntry: userDenylistPDA,
             presaleAta: presaleSaleTokenATA,
             userAta: userSaleTokenATA,
             saleToken: saleToken,
@@ -1643,6 +4036,233 @@ const test = async () => {
       }
       assert(isError, "Test case failed");
     });
+
+    it("Should let referrers claim their rewards once", async () => {
+      await program.methods
+        .claimReferralRewards()
+        .accounts({
+          referrer: user2.publicKey,
+          presaleAccount: presalePDA,
+          referrerAccount: user2ReferrerPDA,
+          presaleAta: presaleSaleTokenATA,
+          referrerAta: user2SaleTokenATA,
+          saleToken: saleToken,
+          denylistEntry: user2DenylistPDA,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user2])
+        .rpc();
+
+      const info = await provider.connection.getTokenAccountBalance(
+        user2SaleTokenATA
+      );
+      assert(info.value.amount == "52000000", "Referral reward claim failed");
+      const referrerAccountData = await program.account.referrerAccount.fetch(
+        user2ReferrerPDA
+      );
+      assert(
+        referrerAccountData.claimedRewardTokens.toString() ==
+          referrerAccountData.rewardTokens.toString(),
+        "Claimed reward tokens update failed"
+      );
+
+      let isError = false;
+      try {
+        await program.methods
+          .claimReferralRewards()
+          .accounts({
+            referrer: user2.publicKey,
+            presaleAccount: presalePDA,
+            referrerAccount: user2ReferrerPDA,
+            presaleAta: presaleSaleTokenATA,
+            referrerAta: user2SaleTokenATA,
+            saleToken: saleToken,
+            denylistEntry: user2DenylistPDA,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([user2])
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6014, "Reward claim check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not allow owner to withdraw unsold tokens without surplus", async () => {
+      let isError = false;
+
+      try {
+        await program.methods
+          .withdrawUnsoldTokens()
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            presaleAta: presaleSaleTokenATA,
+            ownerAta: ownerSaleTokenATA,
+            saleToken: saleToken,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(
+          err.error.errorCode.number == 6022,
+          "Unsold tokens surplus check failed"
+        );
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not allow owner to withdraw more than surplus tokens", async () => {
+      let isError = false;
+
+      try {
+        await program.methods
+          .withdrawSurplusTokens(new anchor.BN(6))
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            presaleAta: presaleSaleTokenATA,
+            ownerAta: ownerSaleTokenATA,
+            saleToken: saleToken,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6022, "Surplus check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should not allow owner to revoke mint authority without mint on claim", async () => {
+      let isError = false;
+
+      try {
+        await program.methods
+          .revokeMintAuthority()
+          .accounts({
+            owner: provider.wallet.publicKey,
+            presaleAccount: presalePDA,
+            saleToken: saleToken,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6029, "Mint mode check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should let the owner set a claim deadline", async () => {
+      let slot = await provider.connection.getSlot();
+      currentTime = await provider.connection.getBlockTime(slot);
+
+      await program.methods
+        .changeClaimDeadline(new anchor.BN(currentTime + 5))
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(
+        presaleAccountData.claimDeadline.toString() == String(currentTime + 5),
+        "Claim deadline update failed"
+      );
+    });
+
+    it("Should not let the owner shorten the claim deadline", async () => {
+      let isError = false;
+      let slot = await provider.connection.getSlot();
+      currentTime = await provider.connection.getBlockTime(slot);
+
+      try {
+        await program.methods
+          .changeClaimDeadline(new anchor.BN(currentTime + 3))
+          .accounts({
+            presaleAccount: presalePDA,
+            owner: provider.wallet.publicKey,
+          })
+          .rpc();
+      } catch (error) {
+        let err = error as anchor.AnchorError;
+        assert(err.error.errorCode.number == 6024, "Deadline check failed");
+        isError = true;
+      }
+      assert(isError, "Test case failed");
+    });
+
+    it("Should let the owner withdraw unclaimed tokens after the claim deadline", async () => {
+      await new Promise((resolve) => setTimeout(resolve, 8000));
+
+      let presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      const unclaimed = presaleAccountData.tokensAdded.sub(
+        presaleAccountData.tokensClaimed
+      );
+      const before = await provider.connection.getTokenAccountBalance(
+        ownerSaleTokenATA
+      );
+
+      await program.methods
+        .withdrawSurplusTokens(unclaimed)
+        .accounts({
+          owner: provider.wallet.publicKey,
+          presaleAccount: presalePDA,
+          presaleAta: presaleSaleTokenATA,
+          ownerAta: ownerSaleTokenATA,
+          saleToken: saleToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert(
+        presaleAccountData.tokensAdded.eq(presaleAccountData.tokensClaimed),
+        "Tokens added update failed"
+      );
+      const after = await provider.connection.getTokenAccountBalance(
+        ownerSaleTokenATA
+      );
+      assert(
+        new anchor.BN(after.value.amount)
+          .sub(new anchor.BN(before.value.amount))
+          .eq(unclaimed.mul(new anchor.BN(1000000))),
+        "Unclaimed tokens transfer failed"
+      );
+    });
+
+    it("Should let the owner close the presale once the claim window closed", async () => {
+      await program.methods
+        .closePresale()
+        .accounts({
+          presaleAccount: presalePDA,
+          owner: provider.wallet.publicKey,
+        })
+        .rpc();
+
+      const presaleAccountData = await program.account.presaleAccount.fetch(
+        presalePDA
+      );
+      assert("closed" in presaleAccountData.status, "Status close failed");
+    });
   });
 };
 